@@ -44,16 +44,22 @@ def gather_data_from_csv(report: Path) -> tuple[list, list]:
     df_data = []
     sum_data = []
     with report.open('r') as file:
-        file.readline() # skip header
-        reader = csv.reader(file, delimiter='\t')
+        # columns are looked up by header, as memoir may add new ones over time
+        reader = csv.DictReader(file, delimiter='\t')
         iprev = 1
         tsprev = None
         total_iteration_memory = 0
-        for i, timestamp, pid, name, memory, cmdline in reader:
-            i = int(i)
-            timestamp = int(timestamp)
-            pid = pid
-            memory = float(memory)
+        for row in reader:
+            if row['Name'].startswith('['):
+                # system-wide values from `--system` and events, not part of the sum
+                continue
+            i = int(row['Iteration'])
+            timestamp = int(row['Timestamp'])
+            # a pid may be reused by another process; older reports have no instance id
+            pid = row.get('Instance') or row['PID']
+            name = row['Name']
+            memory = float(row['Memory MB'])
+            cmdline = row['Command line']
             timestamp = datetime.fromtimestamp(timestamp / 1000)
 
             if not tsprev:
@@ -0,0 +1,172 @@
+//! Memory taken by an hour of daemon history, kept as plain samples and as `History`.
+//!
+//! Run with `cargo bench -p memoir --bench history_memory`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use memoir::history::History;
+use memoir::process::{CurrentProcesses, HistoryEntry, Process};
+
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const PROCESSES: u32 = 1500;
+const SAMPLES: u128 = 3600;
+
+/// A busy build machine sampled every second: most processes idle, a few of them growing,
+/// and a compiler replaced by a new one every few seconds.
+struct Workload {
+    seed: u64,
+    timestamp: u128,
+    next_pid: u32,
+    processes: Vec<(Arc<Process>, u64)>,
+    /// Every process ever started, so only the history itself is measured.
+    spawned: Vec<Arc<Process>>,
+}
+
+impl Workload {
+    fn new() -> Self {
+        let mut workload = Workload {
+            seed: 42,
+            timestamp: 0,
+            next_pid: 1,
+            processes: Vec::new(),
+            spawned: Vec::new(),
+        };
+        for _ in 0..PROCESSES {
+            let process = workload.spawn();
+            workload.processes.push((process, 10));
+        }
+        workload
+    }
+
+    fn random(&mut self, n: usize) -> usize {
+        self.seed = self
+            .seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.seed >> 33) as usize % n
+    }
+
+    fn spawn(&mut self) -> Arc<Process> {
+        let pid = self.next_pid;
+        self.next_pid += 1;
+        let argv = ["/usr/bin/cc1plus", "-c", &format!("src/{pid}.cpp")];
+        let process = Arc::new(Process {
+            pid,
+            ppid: 1,
+            exe: Some(argv[0].into()),
+            argv: argv.iter().map(Into::into).collect(),
+            cwd: Some("/home/ci/build".into()),
+            uid: Some(1000),
+            user: Some("ci".to_string()),
+            env: Vec::new(),
+            cgroup: Some("system.slice/ci.service".to_string()),
+            start_time: Some(self.timestamp as u64),
+        });
+        self.spawned.push(process.clone());
+        process
+    }
+
+    /// Processes are shared between samples, the same way `ProcessCache` does it.
+    fn sample(&mut self) -> CurrentProcesses {
+        for _ in 0..PROCESSES / 100 {
+            let i = self.random(self.processes.len());
+            self.processes[i].1 += self.random(50) as u64;
+        }
+        if self.timestamp.is_multiple_of(5000) {
+            let i = self.random(self.processes.len());
+            self.processes.remove(i);
+            let process = self.spawn();
+            self.processes.push((process, 100));
+        }
+        let entries = self
+            .processes
+            .iter()
+            .map(|(process, memory_mb)| HistoryEntry {
+                process: process.clone(),
+                memory_mb: *memory_mb,
+                peak_mb: Some(*memory_mb),
+                pss_mb: None,
+                uss_mb: None,
+                swap_mb: Some(0),
+                cpu_percent: Some(0.),
+                io: None,
+                state: Some('S'),
+                spread: None,
+            })
+            .collect();
+        let sample = CurrentProcesses {
+            timestamp: self.timestamp,
+            interval_ms: Some(1000),
+            entries,
+            system: None,
+            pressure: None,
+            cgroups: Vec::new(),
+            events: Vec::new(),
+        };
+        self.timestamp += 1000;
+        sample
+    }
+}
+
+/// Bytes taken by whatever `collect` keeps, not counting processes themselves.
+fn measure<T>(name: &str, collect: impl FnOnce(&mut Workload) -> T) -> usize {
+    let mut workload = Workload::new();
+    let start = Instant::now();
+    let kept = collect(&mut workload);
+    let elapsed = start.elapsed();
+    let with = ALLOCATED.load(Ordering::Relaxed);
+    drop(kept);
+    let bytes = with - ALLOCATED.load(Ordering::Relaxed);
+    println!(
+        "{name:>8}: {:>7.1} MB, {SAMPLES} samples stored in {elapsed:.2?}",
+        bytes as f64 / 1e6
+    );
+    bytes
+}
+
+fn main() {
+    let plain = measure("VecDeque", |workload| {
+        (0..SAMPLES)
+            .map(|_| workload.sample())
+            .collect::<VecDeque<_>>()
+    });
+    let history = measure("History", |workload| {
+        let mut history = History::new();
+        for _ in 0..SAMPLES {
+            history.push(workload.sample());
+        }
+        let start = Instant::now();
+        let entries: usize = history.iter().map(|s| s.entries.len()).sum();
+        println!(
+            "History: decoded {entries} entries in {:.2?}",
+            start.elapsed()
+        );
+        history
+    });
+    println!(
+        "History takes {:.1}x less memory",
+        plain as f64 / history as f64
+    );
+}
@@ -1,17 +1,22 @@
-use std::collections::{HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use anyhow::{anyhow, Context, Result};
 use interprocess::local_socket::LocalSocketStream;
 
 use crate::{
+    csvdump::{ExportOptions, Selection},
     daemon,
+    history::History,
     ipc_common::{socket_name, SaveTo, Signal},
-    process::{list_processes, Process},
+    process::{list_processes, MemoryMetric, ProcessCache},
+    source::{self, Backend},
+    tree::ProcessTree,
 };
 
 /// Spawn a separate monitoring process, wait for it to successfully start and
 /// exit immediately leaving it in background.
-pub fn do_detach(history_capacity: usize) -> Result<()> {
+pub fn do_detach(settings: &daemon::Settings) -> Result<()> {
     match daemon::check_socket_status() {
         Ok(daemon::PingResult::DaemonExists) => {
             eprintln!("Daemon already active.");
@@ -25,12 +30,8 @@ pub fn do_detach(history_capacity: usize) -> Result<()> {
     let exe = std::env::current_exe().context("Could not get current executable path")?;
     let mut command = Command::new(exe);
     command
-        .args([
-            "run",
-            "--without-checks",
-            "--keep-history",
-            &history_capacity.to_string(),
-        ])
+        .args(["run", "--without-checks"])
+        .args(settings.to_args())
         .stdin(std::process::Stdio::null())
         .stdout(std::process::Stdio::null())
         .stderr(std::process::Stdio::null());
@@ -86,7 +87,7 @@ pub fn do_detach(history_capacity: usize) -> Result<()> {
 
 /// Run the monitoring daemon, with one thread collecting process statistics and
 /// another listening on a local socket for communication from other memoirctl.
-pub fn do_run(as_daemon: bool, history_capacity: usize) -> Result<()> {
+pub fn do_run(as_daemon: bool, settings: &daemon::Settings) -> Result<()> {
     if as_daemon {
         match daemon::check_socket_status() {
             Ok(daemon::PingResult::DaemonExists) => return Err(anyhow!("Daemon already active.")),
@@ -99,7 +100,7 @@ pub fn do_run(as_daemon: bool, history_capacity: usize) -> Result<()> {
             tmp
         ))?;
     }
-    daemon::run_daemon(history_capacity)
+    daemon::run_daemon(settings)
 }
 
 pub fn do_stop() -> Result<()> {
@@ -110,26 +111,75 @@ pub fn do_status() -> Result<()> {
     match daemon::check_socket_status() {
         Ok(daemon::PingResult::DaemonExists) => {
             eprintln!("Daemon active.");
-            Ok(())
+            request_output(Signal::Status)
         }
         Ok(daemon::PingResult::DaemonNotFound) => Err(anyhow!("Daemon not running.")),
         Err(e) => Err(e).context("Unexpected error during ping"),
     }
 }
 
-pub fn do_once() -> Result<()> {
-    let mut cache: HashSet<std::sync::Arc<Process>> = HashSet::with_capacity(1000);
-    let lp = list_processes(&mut cache)?;
-    let vd = VecDeque::from([lp]);
+/// Print current processes either as a report, or as a tree with cumulative memory usage,
+/// optionally only under the process `root`.
+/// If `interval` is given, two samples are taken that far apart, so CPU and I/O rates
+/// can be reported too.
+pub fn do_once(
+    options: &ExportOptions,
+    tree: bool,
+    root: Option<u32>,
+    interval: Option<Duration>,
+    backend: Backend,
+) -> Result<()> {
+    let mut source = source::open(backend)?;
+    let mut cache = ProcessCache::with_capacity(1000);
+    let mut lp = list_processes(&mut *source, &mut cache)?;
+    if let Some(interval) = interval {
+        std::thread::sleep(interval);
+        lp = list_processes(&mut *source, &mut cache)?;
+    }
+    let selection = Selection::new(options)?;
+    lp.entries.retain(|e| selection.accepts(e));
+    if tree {
+        let tree = ProcessTree::new(&lp.entries);
+        if let Some(pid) = root {
+            if tree.subtree(pid).is_empty() {
+                return Err(anyhow!("No process with PID {pid}"));
+            }
+        }
+        return tree
+            .write_to(&mut std::io::stdout().lock(), options.metric, root)
+            .context("Could not print process tree");
+    }
+    let history = History::from_iter([lp]);
     let mut buffer = Vec::new();
     let writer = std::io::BufWriter::new(&mut buffer);
-    crate::csvdump::save_to_stream(&vd, writer, None)
+    crate::csvdump::save_to_stream(&history, writer, None, options)
         .context("Could not dump process history to buffer")?;
     println!("{}", std::str::from_utf8(buffer.as_slice()).unwrap());
     Ok(())
 }
 
-pub fn do_save(to: &String, last: Option<usize>) -> Result<()> {
+/// Run a command, collecting memory usage of it and its descendants without any daemon.
+/// Saves the report to `output` and returns exit code of the command.
+pub fn do_exec(
+    command: &[String],
+    output: &String,
+    options: &ExportOptions,
+    interval: Duration,
+    backend: Backend,
+    capture_env: &[String],
+) -> Result<i32> {
+    let file = std::env::current_dir()
+        .context("Could not get current directory")?
+        .join(output);
+    let profile = crate::exec::profile_command(command, interval, backend, capture_env)?;
+    crate::report::save_to_file(&profile.history, &file, None, options)
+        .context("Could not save process history to file")?;
+    eprintln!("Report saved to {:?}", file);
+    profile.print_summary(options.metric);
+    Ok(profile.exit.code)
+}
+
+pub fn do_save(to: &String, last: Option<usize>, options: &ExportOptions) -> Result<()> {
     let file = std::env::current_dir()
         .context("Could not get current directory")?
         .join(to);
@@ -143,19 +193,45 @@ pub fn do_save(to: &String, last: Option<usize>) -> Result<()> {
     communicate(Signal::Save {
         to: SaveTo::File { name: filename },
         time_sec: last,
+        options: options.clone(),
     })
 }
 
-pub fn do_dump(last: Option<usize>) -> Result<()> {
+/// Turn a `.memoir` report saved earlier into another format, chosen by extension of `output`.
+pub fn do_convert(input: &String, output: &String, options: &ExportOptions) -> Result<()> {
+    let history = crate::native::read_from_file(Path::new(input))?;
+    crate::report::save_to_file(&history, &PathBuf::from(output), None, options)
+        .context("Could not save converted report")?;
+    eprintln!("Converted {} samples to {:?}", history.len(), output);
+    Ok(())
+}
+
+pub fn do_dump(last: Option<usize>, options: &ExportOptions) -> Result<()> {
     println!("-- requesting dump");
+    request_output(Signal::Save {
+        to: SaveTo::Stdout,
+        time_sec: last,
+        options: options.clone(),
+    })
+}
+
+pub fn do_events(last: Option<usize>) -> Result<()> {
+    println!("-- requesting events");
+    request_output(Signal::Events { time_sec: last })
+}
+
+pub fn do_lifetimes(last: Option<usize>, metric: MemoryMetric) -> Result<()> {
+    println!("-- requesting process lifetimes");
+    request_output(Signal::Lifetimes {
+        time_sec: last,
+        metric,
+    })
+}
+
+/// Send a request to the daemon and print the output it answers with.
+fn request_output(signal: Signal) -> Result<()> {
     let mut conn = connect()?;
-    send(
-        Signal::Save {
-            to: SaveTo::Stdout,
-            time_sec: last,
-        },
-        &mut conn,
-    )?;
+    send(signal, &mut conn)?;
     let r = receive(&mut conn)?;
     match r {
         Signal::Ack => {}
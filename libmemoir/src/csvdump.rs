@@ -1,40 +1,301 @@
-use std::collections::VecDeque;
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use anyhow::Context;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 
-use crate::process::CurrentProcesses;
+use crate::events::Event;
+use crate::history::History;
+use crate::process::{HistoryEntry, MemoryMetric, Process};
+use crate::quote;
+
+/// What and how should be written into a report.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ExportOptions {
+    /// Which value goes into `Memory MB` column.
+    pub metric: MemoryMetric,
+    /// Add per-second I/O rate columns.
+    pub io: bool,
+    /// Add system-wide memory values as pseudo-processes named like `[MemAvailable]`,
+    /// without PID.
+    pub system: bool,
+    /// Write one row per cgroup per iteration, with total memory of its processes and
+    /// the cgroup's own accounting and limits, instead of one row per process.
+    pub by_cgroup: bool,
+    /// Add UID, user, state and working directory columns.
+    pub details: bool,
+    /// Only write processes of these users, given by name or UID.
+    pub users: Vec<String>,
+    /// Only write processes in one of these states, like `D` or `Z`.
+    pub states: Vec<char>,
+    /// Only write processes whose working directory matches this regex.
+    pub cwd: Option<String>,
+}
+
+/// Which processes get into a report, compiled from `ExportOptions`.
+pub(crate) struct Selection {
+    users: Vec<String>,
+    states: Vec<char>,
+    cwd: Option<Regex>,
+}
+
+impl Selection {
+    pub fn new(options: &ExportOptions) -> anyhow::Result<Self> {
+        Ok(Selection {
+            users: options.users.clone(),
+            states: options.states.clone(),
+            cwd: match &options.cwd {
+                Some(cwd) => Some(Regex::new(cwd).context("Invalid working directory regex")?),
+                None => None,
+            },
+        })
+    }
+
+    pub fn accepts(&self, entry: &HistoryEntry) -> bool {
+        let process = &entry.process;
+        let user_matches = |user: &String| {
+            process.user.as_ref() == Some(user)
+                || process.uid.is_some_and(|uid| uid.to_string() == *user)
+        };
+        (self.users.is_empty() || self.users.iter().any(user_matches))
+            && (self.states.is_empty() || entry.state.is_some_and(|s| self.states.contains(&s)))
+            && self.cwd.as_ref().is_none_or(|r| {
+                process
+                    .cwd
+                    .as_ref()
+                    .is_some_and(|cwd| r.is_match(&cwd.to_string_lossy()))
+            })
+    }
+}
 
 pub fn save_to_file(
-    history: &VecDeque<CurrentProcesses>,
+    history: &History,
     destination: &PathBuf,
     time_sec: Option<usize>,
+    options: &ExportOptions,
 ) -> anyhow::Result<()> {
     let writer = csv::WriterBuilder::new()
         .delimiter(b'\t')
         .from_path(destination)
         .context(format!("Could not create CSV writer for {:?}", destination))?;
-    save_to(history, writer, time_sec)
+    save_to(history, writer, time_sec, options)
 }
 
 pub fn save_to_stream<W: std::io::Write>(
-    history: &VecDeque<CurrentProcesses>,
+    history: &History,
     writer: W,
     time_sec: Option<usize>,
+    options: &ExportOptions,
 ) -> anyhow::Result<()> {
     let writer = csv::WriterBuilder::new()
         .delimiter(b'\t')
         .from_writer(writer);
-    save_to(history, writer, time_sec)
+    save_to(history, writer, time_sec, options)
 }
 
 fn save_to<W: std::io::Write>(
-    history: &VecDeque<CurrentProcesses>,
+    history: &History,
     mut writer: csv::Writer<W>,
     time_sec: Option<usize>,
+    options: &ExportOptions,
 ) -> anyhow::Result<()> {
+    let since = since(time_sec)?;
+    if options.by_cgroup {
+        return save_by_cgroup(history, writer, since, options);
+    }
+    let mut header = vec![
+        "Iteration",
+        "Timestamp",
+        "Interval ms",
+        "PID",
+        "PPID",
+        "Instance",
+        "Name",
+        "Memory MB",
+        "Peak MB",
+        "RSS MB",
+        "PSS MB",
+        "USS MB",
+        "Swap MB",
+        "CPU %",
+    ];
+    // only downsampled history has lowest and highest memory different from the memory
+    let downsampled = is_downsampled(history, since);
+    if downsampled {
+        header.extend(["Min MB", "Max MB"]);
+    }
+    if options.io {
+        header.extend(["Read B/s", "Write B/s", "Read calls/s", "Write calls/s"]);
+    }
+    if options.details {
+        header.extend(["UID", "User", "State", "Working directory"]);
+    }
+    // only filled in pressure pseudo-rows
+    let stalls = options.system
+        && history
+            .iter()
+            .any(|p| p.timestamp >= since && p.pressure.is_some());
+    if stalls {
+        header.extend(["Stall avg10 %", "Stall total us"]);
+    }
+    // captured environment variables, in the order they were first seen
+    let env_names = env_names(history, since);
+    let env_columns: Vec<String> = env_names.iter().map(|n| format!("${n}")).collect();
+    header.extend(env_columns.iter().map(String::as_str));
+    header.extend(["Cgroup", "Command line"]);
+    let name_column = header.iter().position(|&h| h == "Name").unwrap();
+    let memory_column = header.iter().position(|&h| h == "Memory MB").unwrap();
+    let pid_column = header.iter().position(|&h| h == "PID").unwrap();
+    let ppid_column = header.iter().position(|&h| h == "PPID").unwrap();
+    let instance_column = header.iter().position(|&h| h == "Instance").unwrap();
+    let cgroup_column = header.iter().position(|&h| h == "Cgroup").unwrap();
+    let stall_column = header
+        .iter()
+        .position(|&h| h == "Stall avg10 %")
+        .unwrap_or_default();
+    let cmdline_column = header.len() - 1;
+    let columns = header.len();
+    let selection = Selection::new(options)?;
+    writer.write_record(header)?;
+    for (iteration, processes) in history.iter().enumerate() {
+        if processes.timestamp < since {
+            continue;
+        }
+        for entry in processes.entries.iter().filter(|e| selection.accepts(e)) {
+            let mut record = vec![
+                (iteration + 1).to_string(),
+                processes.timestamp.to_string(),
+                optional_to_string(processes.interval_ms),
+                entry.process.pid.to_string(),
+                entry.process.ppid.to_string(),
+                entry.process.instance_id(),
+                entry.process.name().into_owned(),
+                optional_to_string(entry.memory(options.metric)),
+                optional_to_string(entry.peak_mb),
+                entry.memory_mb.to_string(),
+                optional_to_string(entry.pss_mb),
+                optional_to_string(entry.uss_mb),
+                optional_to_string(entry.swap_mb),
+                entry
+                    .cpu_percent
+                    .map(|c| format!("{c:.1}"))
+                    .unwrap_or_default(),
+            ];
+            if downsampled {
+                let range = entry.range(options.metric);
+                record.extend([
+                    optional_to_string(range.map(|r| r.0)),
+                    optional_to_string(range.map(|r| r.1)),
+                ]);
+            }
+            if options.io {
+                record.extend([
+                    optional_to_string(entry.io.map(|io| io.read_bytes)),
+                    optional_to_string(entry.io.map(|io| io.write_bytes)),
+                    optional_to_string(entry.io.map(|io| io.syscr)),
+                    optional_to_string(entry.io.map(|io| io.syscw)),
+                ]);
+            }
+            if options.details {
+                let process = &entry.process;
+                record.extend([
+                    optional_to_string(process.uid),
+                    process.user.clone().unwrap_or_default(),
+                    optional_to_string(entry.state),
+                    process
+                        .cwd
+                        .as_ref()
+                        .map(|cwd| quote::quote(cwd.as_os_str()))
+                        .unwrap_or_default(),
+                ]);
+            }
+            if stalls {
+                record.extend([String::new(), String::new()]);
+            }
+            record.extend(env_names.iter().map(|name| {
+                entry
+                    .process
+                    .env
+                    .iter()
+                    .find(|(n, _)| n == name)
+                    .map(|(_, value)| quote::quote(value))
+                    .unwrap_or_default()
+            }));
+            record.push(entry.process.cgroup.clone().unwrap_or_default());
+            record.push(entry.process.commandline());
+            writer.write_record(record)?;
+        }
+        for event in &processes.events {
+            // every process already has rows of its own, so there is no point in repeating
+            // when it started or exited; see `save_lifetimes_to_stream` for that
+            let Event::OomKill {
+                cgroup,
+                victim,
+                memory_mb,
+            } = event
+            else {
+                continue;
+            };
+            let mut record = vec![String::new(); columns];
+            record[0] = (iteration + 1).to_string();
+            record[1] = processes.timestamp.to_string();
+            record[2] = optional_to_string(processes.interval_ms);
+            record[name_column] = format!("[{}]", event.name());
+            record[memory_column] = optional_to_string(*memory_mb);
+            record[cgroup_column] = cgroup.clone().unwrap_or_default();
+            if let Some(victim) = victim {
+                record[pid_column] = victim.pid.to_string();
+                record[ppid_column] = victim.ppid.to_string();
+                record[instance_column] = victim.instance_id();
+                record[cmdline_column] = victim.commandline();
+            }
+            writer.write_record(record)?;
+        }
+        if let Some(system) = processes.system.as_ref().filter(|_| options.system) {
+            for (name, value) in system.fields() {
+                let Some(value) = value else {
+                    continue;
+                };
+                let mut record = vec![String::new(); columns];
+                record[0] = (iteration + 1).to_string();
+                record[1] = processes.timestamp.to_string();
+                record[2] = optional_to_string(processes.interval_ms);
+                record[name_column] = format!("[{name}]");
+                record[memory_column] = value.to_string();
+                writer.write_record(record)?;
+            }
+        }
+        if let Some(pressure) = processes.pressure.as_ref().filter(|_| stalls) {
+            for (resource, stall) in pressure.resources() {
+                let Some(stall) = stall else {
+                    continue;
+                };
+                let lines = [("some", Some(&stall.some)), ("full", stall.full.as_ref())];
+                for (kind, line) in lines {
+                    let Some(line) = line else {
+                        continue;
+                    };
+                    let mut record = vec![String::new(); columns];
+                    record[0] = (iteration + 1).to_string();
+                    record[1] = processes.timestamp.to_string();
+                    record[2] = optional_to_string(processes.interval_ms);
+                    record[name_column] = format!("[{resource} pressure {kind}]");
+                    record[stall_column] = format!("{:.2}", line.avg10);
+                    record[stall_column + 1] = line.total_us.to_string();
+                    writer.write_record(record)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Timestamp of the oldest iteration to be written, if only `time_sec` last seconds are needed.
+pub(crate) fn since(time_sec: Option<usize>) -> anyhow::Result<u128> {
     use std::time::{SystemTime, UNIX_EPOCH};
-    let since = match time_sec {
+    Ok(match time_sec {
         Some(t) => {
             SystemTime::now()
                 .duration_since(UNIX_EPOCH)
@@ -43,33 +304,403 @@ fn save_to<W: std::io::Write>(
                 - (t as u128 * 1_000)
         }
         None => 0,
-    };
+    })
+}
+
+fn is_downsampled(history: &History, since: u128) -> bool {
+    history
+        .iter()
+        .filter(|p| p.timestamp >= since)
+        .any(|p| p.entries.iter().any(|e| e.spread.is_some()))
+}
+
+/// Names of all environment variables captured for processes since `since`.
+fn env_names(history: &History, since: u128) -> Vec<String> {
+    let mut names = Vec::new();
+    for processes in history.iter().filter(|p| p.timestamp >= since) {
+        for entry in &processes.entries {
+            for (name, _) in &entry.process.env {
+                if !names.contains(name) {
+                    names.push(name.clone());
+                }
+            }
+        }
+    }
+    names
+}
+
+/// Write only the events, one per row.
+pub fn save_events_to_stream<W: std::io::Write>(
+    history: &History,
+    writer: W,
+    time_sec: Option<usize>,
+) -> anyhow::Result<()> {
+    let since = since(time_sec)?;
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(writer);
     writer.write_record([
-        "Iteration",
         "Timestamp",
+        "Event",
         "PID",
+        "Instance",
         "Name",
         "Memory MB",
+        "Cgroup",
         "Command line",
     ])?;
+    for processes in history.iter().filter(|p| p.timestamp >= since) {
+        for event in &processes.events {
+            let process = event.process();
+            writer.write_record(&[
+                processes.timestamp.to_string(),
+                event.name().to_string(),
+                optional_to_string(process.map(|p| p.pid)),
+                process.map(|p| p.instance_id()).unwrap_or_default(),
+                process.map(|p| p.name().into_owned()).unwrap_or_default(),
+                optional_to_string(event.memory_mb()),
+                event.cgroup().unwrap_or_default().to_string(),
+                process.map(|p| p.commandline()).unwrap_or_default(),
+            ])?;
+        }
+    }
+    Ok(())
+}
+
+/// Write one row per process instance seen in the history: when it started and exited,
+/// and how much memory it used meanwhile.
+pub fn save_lifetimes_to_stream<W: std::io::Write>(
+    history: &History,
+    writer: W,
+    time_sec: Option<usize>,
+    metric: MemoryMetric,
+) -> anyhow::Result<()> {
+    struct Lifetime {
+        process: Arc<Process>,
+        first_seen: u128,
+        last_seen: u128,
+        exited: Option<u128>,
+        samples: u64,
+        /// Samples the memory is known for, which the average is taken over.
+        measured: u64,
+        total_mb: u64,
+        peak_mb: Option<u64>,
+    }
+    let since = since(time_sec)?;
+    let mut lifetimes: HashMap<String, Lifetime> = HashMap::new();
+    for processes in history.iter().filter(|p| p.timestamp >= since) {
+        for entry in &processes.entries {
+            let memory = entry.memory(metric);
+            // a bucket of downsampled history counts as all the samples it was made of
+            let samples = entry.spread.map_or(1, |s| s.samples) as u64;
+            let lifetime = lifetimes
+                .entry(entry.process.instance_id())
+                .or_insert_with(|| Lifetime {
+                    process: entry.process.clone(),
+                    first_seen: processes.timestamp,
+                    last_seen: processes.timestamp,
+                    exited: None,
+                    samples: 0,
+                    measured: 0,
+                    total_mb: 0,
+                    peak_mb: None,
+                });
+            // command line might have changed after exec()
+            lifetime.process = entry.process.clone();
+            lifetime.last_seen = processes.timestamp;
+            // it only looked like an exit, e.g. the process went below --min-memory for a while
+            lifetime.exited = None;
+            lifetime.samples += samples;
+            if let Some(memory) = memory {
+                lifetime.measured += samples;
+                lifetime.total_mb += memory * samples;
+            }
+            let peak = entry.range(metric).map(|r| r.1);
+            lifetime.peak_mb = lifetime.peak_mb.max(peak);
+        }
+        for event in &processes.events {
+            if let Event::Exited { process, .. } = event {
+                if let Some(lifetime) = lifetimes.get_mut(&process.instance_id()) {
+                    lifetime.exited = Some(processes.timestamp);
+                }
+            }
+        }
+    }
+    let mut lifetimes: Vec<Lifetime> = lifetimes.into_values().collect();
+    lifetimes.sort_by_key(|l| (l.first_seen, l.process.pid));
+
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(writer);
+    writer.write_record([
+        "Instance",
+        "PID",
+        "Name",
+        "Start",
+        "End",
+        "Duration s",
+        "Samples",
+        "Peak MB",
+        "Average MB",
+        "Command line",
+    ])?;
+    for l in lifetimes {
+        // the process might have been started long before the history begins
+        let start = l.process.start_time.map(u128::from).unwrap_or(l.first_seen);
+        let end = l.exited.unwrap_or(l.last_seen);
+        writer.write_record(&[
+            l.process.instance_id(),
+            l.process.pid.to_string(),
+            l.process.name().into_owned(),
+            start.to_string(),
+            optional_to_string(l.exited),
+            format!("{:.1}", end.saturating_sub(start) as f64 / 1000.),
+            l.samples.to_string(),
+            optional_to_string(l.peak_mb),
+            optional_to_string(
+                (l.measured > 0).then(|| (l.total_mb + l.measured / 2) / l.measured),
+            ),
+            l.process.commandline(),
+        ])?;
+    }
+    Ok(())
+}
+
+fn save_by_cgroup<W: std::io::Write>(
+    history: &History,
+    mut writer: csv::Writer<W>,
+    since: u128,
+    options: &ExportOptions,
+) -> anyhow::Result<()> {
+    let selection = Selection::new(options)?;
+    writer.write_record([
+        "Iteration",
+        "Timestamp",
+        "Interval ms",
+        "Cgroup",
+        "Processes",
+        "Memory MB",
+        "Current MB",
+        "Max MB",
+        "Peak MB",
+        "High events",
+        "Max events",
+        "OOM events",
+        "OOM kills",
+    ])?;
     for (iteration, processes) in history.iter().enumerate() {
         if processes.timestamp < since {
             continue;
         }
-        for entry in &processes.entries {
+        // memory of a group is unknown if it is for any of its processes
+        let mut groups: HashMap<&str, (usize, Option<u64>)> = HashMap::new();
+        for entry in processes.entries.iter().filter(|e| selection.accepts(e)) {
+            let cgroup = entry.process.cgroup.as_deref().unwrap_or_default();
+            let group = groups.entry(cgroup).or_insert((0, Some(0)));
+            group.0 += 1;
+            group.1 = group
+                .1
+                .zip(entry.memory(options.metric))
+                .map(|(a, b)| a + b);
+        }
+        let mut names: Vec<&str> = groups.keys().copied().collect();
+        names.sort();
+        for name in names {
+            let (count, memory) = groups[name];
+            let accounting = processes.cgroups.iter().find(|c| c.path == name);
+            let events = accounting.and_then(|c| c.events);
             writer.write_record(&[
                 (iteration + 1).to_string(),
                 processes.timestamp.to_string(),
-                entry.process.pid.to_string(),
-                entry.process.name.to_string(),
-                entry.memory_mb.to_string(),
-                escape_cmdline(&entry.process.commandline),
+                optional_to_string(processes.interval_ms),
+                name.to_string(),
+                count.to_string(),
+                optional_to_string(memory),
+                optional_to_string(accounting.and_then(|c| c.current_mb)),
+                optional_to_string(accounting.and_then(|c| c.max_mb)),
+                optional_to_string(accounting.and_then(|c| c.peak_mb)),
+                optional_to_string(events.map(|e| e.high)),
+                optional_to_string(events.map(|e| e.max)),
+                optional_to_string(events.map(|e| e.oom)),
+                optional_to_string(events.map(|e| e.oom_kill)),
             ])?;
         }
     }
     Ok(())
 }
 
-fn escape_cmdline(cmdline: &str) -> String {
-    cmdline.replace('\t', "\\t").replace('\n', "\\n")
+fn optional_to_string<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::{
+        list_processes, Pressure, ProcessCache, Stall, StallRecord, SystemMemory,
+    };
+    use crate::source::{ScriptedSample, ScriptedSource};
+
+    #[test]
+    fn report() {
+        let mut first = ScriptedSample::new(1_000)
+            .with_process(1, 0, "/sbin/init", 10)
+            .with_process(100, 1, "/usr/bin/cc1plus main.cpp\t-o main.o", 300);
+        first.processes[1].1.cpu_time_ms = Some(0);
+        let mut second = ScriptedSample::new(2_000)
+            .with_process(1, 0, "/sbin/init", 10)
+            .with_process(100, 1, "/usr/bin/cc1plus main.cpp\t-o main.o", 450);
+        second.processes[1].1.cpu_time_ms = Some(500);
+        let mut source = ScriptedSource::new([first, second]);
+        let mut cache = ProcessCache::default();
+        let history = History::from_iter([
+            list_processes(&mut source, &mut cache).unwrap(),
+            list_processes(&mut source, &mut cache).unwrap(),
+        ]);
+
+        let mut out = Vec::new();
+        save_to_stream(&history, &mut out, None, &ExportOptions::default()).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&out).unwrap(),
+            "Iteration\tTimestamp\tInterval ms\tPID\tPPID\tInstance\tName\tMemory MB\t\
+            Peak MB\tRSS MB\tPSS MB\tUSS MB\tSwap MB\tCPU %\tCgroup\tCommand line\n\
+            1\t1000\t\t1\t0\t1\t/sbin/init\t10\t\t10\t\t\t\t\t\t/sbin/init\n\
+            1\t1000\t\t100\t1\t100\t/usr/bin/cc1plus\t300\t\t300\t\t\t\t\t\t\
+            /usr/bin/cc1plus $'main.cpp\\t-o' main.o\n\
+            2\t2000\t1000\t1\t0\t1\t/sbin/init\t10\t\t10\t\t\t\t\t\t/sbin/init\n\
+            2\t2000\t1000\t100\t1\t100\t/usr/bin/cc1plus\t450\t\t450\t\t\t\t50.0\t\t\
+            /usr/bin/cc1plus $'main.cpp\\t-o' main.o\n"
+        );
+    }
+
+    #[test]
+    fn unknown_metric() {
+        // smaps_rollup of the other user's process could not be read
+        let mut sample = ScriptedSample::new(1_000)
+            .with_process(1, 0, "init", 10)
+            .with_process(100, 1, "make", 20);
+        sample.processes[1].0.pss_mb = Some(15);
+        let history = History::from_iter([sample.into_sample()]);
+
+        let options = ExportOptions {
+            metric: MemoryMetric::Pss,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        save_to_stream(&history, &mut out, None, &options).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&out).unwrap(),
+            "Iteration\tTimestamp\tInterval ms\tPID\tPPID\tInstance\tName\tMemory MB\t\
+            Peak MB\tRSS MB\tPSS MB\tUSS MB\tSwap MB\tCPU %\tCgroup\tCommand line\n\
+            1\t1000\t\t1\t0\t1\tinit\t\t\t10\t\t\t\t\t\tinit\n\
+            1\t1000\t\t100\t1\t100\tmake\t15\t\t20\t15\t\t\t\t\tmake\n"
+        );
+
+        let mut out = Vec::new();
+        save_lifetimes_to_stream(&history, &mut out, None, MemoryMetric::Pss).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\n1\t1\tinit\t1000\t\t0.0\t1\t\t\tinit\n"));
+    }
+
+    #[test]
+    fn system() {
+        let mut sample = ScriptedSample::new(1_000).with_process(1, 0, "init", 10);
+        sample.system = Some(SystemMemory {
+            total_mb: 16_000,
+            available_mb: Some(9_000),
+            free_mb: 2_000,
+            buffers_mb: None,
+            cached_mb: None,
+            shmem_mb: None,
+            slab_mb: None,
+            swap_total_mb: None,
+            swap_used_mb: None,
+        });
+        let history = History::from_iter([sample.into_sample()]);
+
+        let mut out = Vec::new();
+        save_to_stream(&history, &mut out, None, &ExportOptions::default()).unwrap();
+        assert!(!std::str::from_utf8(&out).unwrap().contains("[MemTotal]"));
+
+        let options = ExportOptions {
+            system: true,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        save_to_stream(&history, &mut out, None, &options).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&out).unwrap(),
+            "Iteration\tTimestamp\tInterval ms\tPID\tPPID\tInstance\tName\tMemory MB\t\
+            Peak MB\tRSS MB\tPSS MB\tUSS MB\tSwap MB\tCPU %\tCgroup\tCommand line\n\
+            1\t1000\t\t1\t0\t1\tinit\t10\t\t10\t\t\t\t\t\tinit\n\
+            1\t1000\t\t\t\t\t[MemTotal]\t16000\t\t\t\t\t\t\t\t\n\
+            1\t1000\t\t\t\t\t[MemAvailable]\t9000\t\t\t\t\t\t\t\t\n\
+            1\t1000\t\t\t\t\t[MemFree]\t2000\t\t\t\t\t\t\t\t\n"
+        );
+    }
+
+    #[test]
+    fn pressure() {
+        let record = |avg10, total_us| StallRecord { avg10, total_us };
+        let mut sample = ScriptedSample::new(1_000).with_process(1, 0, "init", 10);
+        sample.pressure = Some(Pressure {
+            cpu: None,
+            memory: Some(Stall {
+                some: record(12.5, 3_000_000),
+                full: Some(record(0.25, 40_000)),
+            }),
+            io: None,
+        });
+        let history = History::from_iter([sample.into_sample()]);
+
+        let options = ExportOptions {
+            system: true,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        save_to_stream(&history, &mut out, None, &options).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&out).unwrap(),
+            "Iteration\tTimestamp\tInterval ms\tPID\tPPID\tInstance\tName\tMemory MB\t\
+            Peak MB\tRSS MB\tPSS MB\tUSS MB\tSwap MB\tCPU %\tStall avg10 %\tStall total us\t\
+            Cgroup\tCommand line\n\
+            1\t1000\t\t1\t0\t1\tinit\t10\t\t10\t\t\t\t\t\t\t\tinit\n\
+            1\t1000\t\t\t\t\t[memory pressure some]\t\t\t\t\t\t\t\t12.50\t3000000\t\t\n\
+            1\t1000\t\t\t\t\t[memory pressure full]\t\t\t\t\t\t\t\t0.25\t40000\t\t\n"
+        );
+    }
+
+    #[test]
+    fn lifetimes() {
+        let processes = ScriptedSample::new(0)
+            .with_process(1, 0, "init", 10)
+            .with_process(100, 1, "server", 10)
+            .with_process(200, 1, "job", 30);
+        let memory: [&[(u32, u64)]; 4] = [
+            &[(1, 10), (100, 10), (200, 30)],
+            // server is gone for a sample, e.g. filtered out, and then back
+            &[(1, 10), (200, 30)],
+            &[(1, 10), (100, 11)],
+            &[(1, 10), (100, 11)],
+        ];
+        let mut history = History::new();
+        let mut previous = Vec::new();
+        for (i, memory) in memory.into_iter().enumerate() {
+            let mut sample = processes.at(i as u128 * 1000, memory).into_sample();
+            sample.events = crate::events::lifecycle(&previous, &sample.entries);
+            previous = sample.entries.clone();
+            history.push(sample);
+        }
+
+        let mut out = Vec::new();
+        save_lifetimes_to_stream(&history, &mut out, None, MemoryMetric::Rss).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&out).unwrap(),
+            "Instance\tPID\tName\tStart\tEnd\tDuration s\tSamples\tPeak MB\tAverage MB\t\
+            Command line\n\
+            1\t1\tinit\t0\t\t3.0\t4\t10\t10\tinit\n\
+            100\t100\tserver\t0\t\t3.0\t3\t11\t11\tserver\n\
+            200\t200\tjob\t0\t2000\t2.0\t2\t30\t30\tjob\n"
+        );
+    }
 }
@@ -1,34 +1,180 @@
-use std::collections::{HashSet, VecDeque};
+use std::collections::HashMap;
 use std::io::ErrorKind;
 use std::path::PathBuf;
 use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context, Result};
 use interprocess::local_socket::{LocalSocketListener, LocalSocketStream};
 
 use crate::csvdump;
+use crate::events;
+use crate::filter::Filter;
 use crate::ipc_common::{socket_name, SaveTo, Signal};
-use crate::process::{list_processes, CurrentProcesses, Process};
+use crate::process::{list_processes, CurrentProcesses, Process, ProcessCache};
+use crate::report;
+use crate::retention::Retention;
+use crate::segments::{self, SegmentLog};
+use crate::source::{self, Backend, ProcessSource};
 
-type ProcessHistory = Arc<Mutex<VecDeque<CurrentProcesses>>>;
+type ProcessHistory = Arc<Mutex<Retention>>;
 const CLEANUP_INTERVAL: usize = 100;
 
+/// How the daemon collects and keeps process history.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// How long to keep samples for, as they were taken.
+    pub keep_history: Duration,
+    /// Coarser history to keep for longer after that, finest first.
+    pub tiers: Vec<Tier>,
+    /// Time between two samples. With `burst`, this is the rate used while memory usage
+    /// stays more or less the same.
+    pub interval: Duration,
+    /// Sample more often while memory usage is growing.
+    pub burst: Option<Burst>,
+    /// Which processes to record.
+    pub filter: Filter,
+    /// Where to collect processes from.
+    pub backend: Backend,
+    /// Environment variables to record for every process.
+    pub capture_env: Vec<String>,
+    /// Most memory history and the process cache may take, in bytes.
+    pub max_history_memory: Option<u64>,
+    /// Directory to log samples to as they are taken, and to load history from on start.
+    pub data_dir: Option<PathBuf>,
+}
+
+/// Faster sampling rate to switch to when memory usage starts growing fast.
+#[derive(Clone, Debug)]
+pub struct Burst {
+    /// Time between two samples while bursting.
+    pub interval: Duration,
+    /// Growth of total memory or memory of a single process, in MB per second, which
+    /// starts a burst. The burst ends when total memory changes slower than that.
+    pub threshold_mb: u64,
+}
+
+/// Samples older than `keep_history` merged into buckets, with lowest, average and highest
+/// memory of every process in a bucket.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tier {
+    /// Length of a bucket.
+    pub resolution: Duration,
+    /// How old buckets are kept, before being merged into the next tier or dropped.
+    pub keep: Duration,
+}
+
+impl Settings {
+    /// Command line arguments for `memoirctl run` to start a daemon with the same settings.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "--keep-history".to_string(),
+            format!("{}ms", self.keep_history.as_millis()),
+            "--interval".to_string(),
+            format!("{}ms", self.interval.as_millis()),
+        ];
+        if let Some(burst) = &self.burst {
+            args.extend([
+                "--burst-interval".to_string(),
+                format!("{}ms", burst.interval.as_millis()),
+                "--burst-threshold".to_string(),
+                burst.threshold_mb.to_string(),
+            ]);
+        }
+        for tier in &self.tiers {
+            args.extend([
+                "--downsample".to_string(),
+                format!(
+                    "{}ms:{}ms",
+                    tier.resolution.as_millis(),
+                    tier.keep.as_millis()
+                ),
+            ]);
+        }
+        args.extend(self.filter.to_args());
+        for name in &self.capture_env {
+            args.extend(["--capture-env".to_string(), name.clone()]);
+        }
+        if let Some(max) = self.max_history_memory {
+            args.extend(["--max-history-memory".to_string(), max.to_string()]);
+        }
+        if let Some(dir) = &self.data_dir {
+            args.extend(["--data-dir".to_string(), dir.to_string_lossy().into_owned()]);
+        }
+        if self.backend != Backend::default() {
+            args.extend(["--backend".to_string(), self.backend.to_string()]);
+        }
+        args
+    }
+
+    /// How old the oldest kept history gets.
+    fn longest_keep(&self) -> Duration {
+        self.tiers.last().map_or(self.keep_history, |t| t.keep)
+    }
+}
+
 pub enum PingResult {
     DaemonExists,
     DaemonNotFound,
 }
 
 /// Run a daemon-server listening to a LocalSocket. Blocks until the daemon is stopped.
-pub fn run_daemon(history_capacity: usize) -> Result<()> {
-    eprintln!("Using history capacity of {history_capacity} seconds");
-    let history = Arc::new(Mutex::new(VecDeque::with_capacity(history_capacity)));
+pub fn run_daemon(settings: &Settings) -> Result<()> {
+    run_daemon_with(
+        &mut *source::open(settings.backend)?,
+        settings,
+        &socket_name(),
+    )
+}
+
+/// Same as `run_daemon`, but with processes coming from given source and listening
+/// at given socket.
+pub fn run_daemon_with<S: ProcessSource + ?Sized>(
+    source: &mut S,
+    settings: &Settings,
+    socket_name: &str,
+) -> Result<()> {
+    eprintln!(
+        "Keeping {:?} of history, sampling every {:?}",
+        settings.keep_history, settings.interval
+    );
+    if let Some(burst) = &settings.burst {
+        eprintln!(
+            "Sampling every {:?} while memory grows faster than {} MB/s",
+            burst.interval, burst.threshold_mb
+        );
+    }
+    for tier in &settings.tiers {
+        eprintln!(
+            "Then keeping {:?} buckets of history up to {:?} old",
+            tier.resolution, tier.keep
+        );
+    }
+    if let Some(max) = settings.max_history_memory {
+        eprintln!("Keeping history and process cache within {max} bytes");
+    }
+    let mut retention = Retention::new(settings.keep_history, &settings.tiers)?
+        .with_max_bytes(settings.max_history_memory.map(|m| m as usize));
+    let mut cache = ProcessCache::with_capacity(1000)
+        .with_filter(settings.filter.clone())
+        .with_captured_env(settings.capture_env.clone());
+    if let Some(dir) = &settings.data_dir {
+        // loaded processes go through the cache, so ones still running are not told apart
+        // from themselves in samples taken from now on
+        let loaded = segments::replay(dir, |mut sample| {
+            cache.adopt(&mut sample);
+            retention.push(sample, cache.approx_bytes());
+        })
+        .context(format!("Could not load history from {:?}", dir))?;
+        eprintln!("Loaded {loaded} samples from {:?}", dir);
+    }
+    let history = Arc::new(Mutex::new(retention));
 
     let (snd, rcv) = std::sync::mpsc::channel();
-    let ipc = fork_ipc(snd, history.clone()).context("Error: failed to setup IPC")?;
-    run_process_list_daemon(rcv, history.clone(), history_capacity)?;
+    let ipc = fork_ipc(snd, history.clone(), socket_name).context("Error: failed to setup IPC")?;
+    run_process_list_daemon(source, cache, rcv, history.clone(), settings)?;
     ipc.join().unwrap()
 }
 
@@ -64,14 +210,15 @@ pub fn check_socket_status() -> Result<PingResult> {
 fn fork_ipc(
     finish_snd: Sender<()>,
     process_history: ProcessHistory,
+    socket_name: &str,
 ) -> Result<thread::JoinHandle<Result<()>>> {
-    let listener = match LocalSocketListener::bind(socket_name()) {
+    let listener = match LocalSocketListener::bind(socket_name) {
         Err(e) if e.kind() == ErrorKind::AddrInUse => {
             // TODO: detect if other instance of memoir is actually running
             eprintln!(
                 "Error: could not start server because the socket file is occupied. \
                 Check if {} is in use by another memoir process and try again.",
-                socket_name(),
+                socket_name,
             );
             return Err(anyhow!(e));
         }
@@ -81,34 +228,111 @@ fn fork_ipc(
         }
         Ok(x) => x,
     };
-    println!("Server running at {}", socket_name());
+    println!("Server running at {}", socket_name);
     let handle = thread::spawn(move || ipc_listen(finish_snd, listener, process_history));
     Ok(handle)
 }
 
-pub fn run_process_list_daemon(
+pub fn run_process_list_daemon<S: ProcessSource + ?Sized>(
+    source: &mut S,
+    mut cache: ProcessCache,
     finish_rcv: Receiver<()>,
     history: ProcessHistory,
-    history_capacity: usize,
+    settings: &Settings,
 ) -> Result<()> {
-    let mut cache: HashSet<Arc<Process>> = HashSet::with_capacity(1000);
+    let mut log = match &settings.data_dir {
+        Some(dir) => Some(SegmentLog::new(dir, settings.longest_keep())?),
+        None => None,
+    };
     let mut cleanup_tick = 0;
-    // 1 second wait between process polls is done via recv() timeout
-    while listing_should_continue(&finish_rcv, Duration::new(1, 0)) {
+    let mut first = true;
+    let mut pacer = Pacer::default();
+    let mut next_sample = Instant::now();
+    // wait between process polls is done via recv() timeout, and accounts for the time
+    // spent on listing processes, so sampling rate stays the same
+    while listing_should_continue(
+        &finish_rcv,
+        next_sample.saturating_duration_since(Instant::now()),
+    ) {
         cleanup_tick += 1;
-        let mut locked = history.lock().unwrap();
-        locked.push_back(list_processes(&mut cache)?);
-        if locked.len() > history_capacity {
-            locked.pop_front();
+        let mut sample = list_processes(source, &mut cache)?;
+        let interval = pacer.next_interval(settings, &sample);
+        next_sample = (next_sample + interval).max(Instant::now());
+        // only this thread adds samples, so the history does not change until the push below
+        let previous = {
+            let locked = history.lock().unwrap();
+            let samples = locked.samples();
+            (!samples.is_empty()).then(|| samples.last_entries())
+        };
+        // processes running when the daemon starts are not reported as started, nor are
+        // processes which exited while it was not running
+        if let Some(previous) = previous.filter(|_| !first) {
+            sample
+                .events
+                .extend(events::lifecycle(&previous, &sample.entries));
+        }
+        first = false;
+        // writing is synced to disk, and requests to the daemon should not wait for that
+        if let Some(log) = &mut log {
+            if let Err(e) = log.append(&sample) {
+                eprintln!("Error: could not log sample: {:#}", e);
+            }
         }
+        history.lock().unwrap().push(sample, cache.approx_bytes());
         if cleanup_tick >= CLEANUP_INTERVAL {
             cleanup_tick = 0;
-            cache.retain(|c| Arc::strong_count(c) > 1);
+            cache.cleanup();
         }
     }
     Ok(())
 }
 
+/// Chooses the time until the next sample, depending on how fast memory usage has changed
+/// since the previous one.
+#[derive(Default)]
+struct Pacer {
+    total_mb: u64,
+    processes: HashMap<Arc<Process>, u64>,
+    bursting: bool,
+}
+
+impl Pacer {
+    fn next_interval(&mut self, settings: &Settings, sample: &CurrentProcesses) -> Duration {
+        let Some(burst) = &settings.burst else {
+            return settings.interval;
+        };
+        let total_mb = sample.entries.iter().map(|e| e.memory_mb).sum();
+        if let Some(elapsed_ms) = sample.interval_ms.filter(|&e| e > 0) {
+            let per_second = |before: u64, after: u64| {
+                (after as f64 - before as f64) * 1000. / elapsed_ms as f64
+            };
+            let threshold = burst.threshold_mb as f64;
+            let total_rate = per_second(self.total_mb, total_mb);
+            let process_growing = sample.entries.iter().any(|e| {
+                self.processes
+                    .get(&e.process)
+                    .is_some_and(|&before| per_second(before, e.memory_mb) > threshold)
+            });
+            if total_rate > threshold || process_growing {
+                self.bursting = true;
+            } else if total_rate.abs() <= threshold {
+                self.bursting = false;
+            }
+        }
+        self.total_mb = total_mb;
+        self.processes = sample
+            .entries
+            .iter()
+            .map(|e| (e.process.clone(), e.memory_mb))
+            .collect();
+        if self.bursting {
+            burst.interval
+        } else {
+            settings.interval
+        }
+    }
+}
+
 fn listing_should_continue(finish_rcv: &Receiver<()>, timeout: Duration) -> bool {
     match finish_rcv.recv_timeout(timeout) {
         Ok(_) => false,
@@ -131,38 +355,54 @@ fn ipc_listen(
         println!("Incoming connection!");
         let received =
             Signal::read_from(&mut conn).context("Could not read signal from connection");
-        if received.is_err() {
-            let mut uw = received.unwrap_err();
-            let feed_result = Signal::Error
-                .feed_into(&mut conn)
-                .context("Also could not respond with error to connection");
-            if feed_result.is_err() {
-                uw = uw.context(feed_result.unwrap_err());
+        let received = match received {
+            Err(mut uw) => {
+                let feed_result = Signal::Error
+                    .feed_into(&mut conn)
+                    .context("Also could not respond with error to connection");
+                if let Err(fe) = feed_result {
+                    uw = uw.context(fe);
+                }
+                return Err(uw);
             }
-            return Err(uw);
-        } else {
-            Signal::Ack
-                .feed_into(&mut conn)
-                .context("Could not respond with ack to connection")?;
-        }
-        match received.unwrap() {
+            Ok(r) => {
+                Signal::Ack
+                    .feed_into(&mut conn)
+                    .context("Could not respond with ack to connection")?;
+                r
+            }
+        };
+        match received {
             Signal::Stop => {
                 finish_snd
                     .send(())
                     .context("Error: could not send stop signal")?;
                 break;
             }
-            Signal::Save { to, time_sec } => match to {
+            Signal::Save {
+                to,
+                time_sec,
+                options,
+            } => match to {
                 SaveTo::File { name } => {
                     eprintln!("Saving current process info to {:?}...", name);
-                    csvdump::save_to_file(&history.lock().unwrap(), &PathBuf::from(name), time_sec)
-                        .context("Could not dump process history to CSV file")?;
+                    let locked = history.lock().unwrap();
+                    report::save_to_file(
+                        &locked.window(csvdump::since(time_sec)?),
+                        &PathBuf::from(name),
+                        time_sec,
+                        &options,
+                    )
+                    .context("Could not save process history to file")?;
                 }
                 SaveTo::Stdout => {
                     let mut buffer = Vec::new();
                     let writer = std::io::BufWriter::new(&mut buffer);
-                    csvdump::save_to_stream(&history.lock().unwrap(), writer, time_sec)
+                    let locked = history.lock().unwrap();
+                    let window = locked.window(csvdump::since(time_sec)?);
+                    csvdump::save_to_stream(&window, writer, time_sec, &options)
                         .context("Could not dump process history to buffer")?;
+                    drop(locked);
                     Signal::Output {
                         output: std::str::from_utf8(buffer.as_slice()).unwrap().to_string(),
                     }
@@ -170,6 +410,40 @@ fn ipc_listen(
                     .context("Could not feed dump into connection")?;
                 }
             },
+            Signal::Status => {
+                let status = history.lock().unwrap().status();
+                Signal::Output { output: status }
+                    .feed_into(&mut conn)
+                    .context("Could not feed status into connection")?;
+            }
+            Signal::Events { time_sec } => {
+                let mut buffer = Vec::new();
+                let writer = std::io::BufWriter::new(&mut buffer);
+                let locked = history.lock().unwrap();
+                let window = locked.window(csvdump::since(time_sec)?);
+                csvdump::save_events_to_stream(&window, writer, time_sec)
+                    .context("Could not dump events to buffer")?;
+                drop(locked);
+                Signal::Output {
+                    output: std::str::from_utf8(buffer.as_slice()).unwrap().to_string(),
+                }
+                .feed_into(&mut conn)
+                .context("Could not feed events into connection")?;
+            }
+            Signal::Lifetimes { time_sec, metric } => {
+                let mut buffer = Vec::new();
+                let writer = std::io::BufWriter::new(&mut buffer);
+                let locked = history.lock().unwrap();
+                let window = locked.window(csvdump::since(time_sec)?);
+                csvdump::save_lifetimes_to_stream(&window, writer, time_sec, metric)
+                    .context("Could not dump process lifetimes to buffer")?;
+                drop(locked);
+                Signal::Output {
+                    output: std::str::from_utf8(buffer.as_slice()).unwrap().to_string(),
+                }
+                .feed_into(&mut conn)
+                .context("Could not feed process lifetimes into connection")?;
+            }
             x => {
                 eprintln!("Unexpected signal: {x:?}");
             }
@@ -190,3 +464,155 @@ fn handle_ipc_connection_error(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::ScriptedSample;
+
+    /// Send a signal to the daemon and wait for it to be acknowledged.
+    fn send(socket_name: &str, signal: Signal) -> Result<LocalSocketStream> {
+        let mut conn = LocalSocketStream::connect(socket_name)?;
+        signal.feed_into(&mut conn)?;
+        match Signal::read_from(&mut conn)? {
+            Signal::Ack => Ok(conn),
+            x => Err(anyhow!("Unexpected response: {x:?}")),
+        }
+    }
+
+    /// Ask the daemon for something it answers to with an output.
+    fn request(socket_name: &str, signal: Signal) -> Result<String> {
+        let mut conn = send(socket_name, signal)?;
+        match Signal::read_from(&mut conn)? {
+            Signal::Output { output } => Ok(output),
+            x => Err(anyhow!("Unexpected response: {x:?}")),
+        }
+    }
+
+    #[test]
+    fn daemon_over_ipc() {
+        use crate::source::{ScriptedSample, ScriptedSource};
+        use interprocess::local_socket::NameTypeSupport;
+
+        let name = format!("memoir-daemon-test-{}.sock", std::process::id());
+        let name = match NameTypeSupport::query() {
+            NameTypeSupport::OnlyPaths => std::env::temp_dir().join(name).display().to_string(),
+            _ => format!("@{name}"),
+        };
+        let script = [
+            ScriptedSample::new(1_000)
+                .with_process(1, 0, "init", 10)
+                .with_process(100, 1, "make all", 20),
+            ScriptedSample::new(2_000)
+                .with_process(1, 0, "init", 10)
+                .with_process(100, 1, "make all", 20)
+                .with_process(101, 100, "cc1plus main.cpp", 200),
+            ScriptedSample::new(3_000)
+                .with_process(1, 0, "init", 10)
+                .with_process(100, 1, "make all", 20),
+        ];
+        let daemon_name = name.clone();
+        let daemon = thread::spawn(move || {
+            let settings = Settings {
+                keep_history: Duration::from_secs(3600),
+                tiers: Vec::new(),
+                interval: Duration::from_millis(10),
+                burst: None,
+                filter: Filter::default(),
+                backend: Backend::Native,
+                capture_env: Vec::new(),
+                max_history_memory: None,
+                data_dir: None,
+            };
+            run_daemon_with(&mut ScriptedSource::new(script), &settings, &daemon_name)
+        });
+
+        // the last sample is repeated once the script is over, which does not produce events
+        let mut events = String::new();
+        for _ in 0..200 {
+            thread::sleep(Duration::from_millis(10));
+            events = request(&name, Signal::Events { time_sec: None }).unwrap_or_default();
+            if events.contains("exited") {
+                break;
+            }
+        }
+        assert_eq!(
+            events,
+            "Timestamp\tEvent\tPID\tInstance\tName\tMemory MB\tCgroup\tCommand line\n\
+            2000\tstarted\t101\t101\tcc1plus\t200\t\tcc1plus main.cpp\n\
+            3000\texited\t101\t101\tcc1plus\t200\t\tcc1plus main.cpp\n"
+        );
+
+        let dump = request(
+            &name,
+            Signal::Save {
+                to: SaveTo::Stdout,
+                time_sec: None,
+                options: Default::default(),
+            },
+        )
+        .unwrap();
+        let rows: Vec<String> = dump
+            .lines()
+            .skip(1)
+            .take(7)
+            .map(|l| {
+                let columns: Vec<&str> = l.split('\t').collect();
+                columns[..4].join(" ")
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                "1 1000  1",
+                "1 1000  100",
+                "2 2000 1000 1",
+                "2 2000 1000 100",
+                "2 2000 1000 101",
+                "3 3000 1000 1",
+                "3 3000 1000 100",
+            ]
+        );
+
+        send(&name, Signal::Stop).unwrap();
+        daemon.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn adaptive_interval() {
+        let slow = Duration::from_secs(5);
+        let fast = Duration::from_millis(250);
+        let settings = Settings {
+            keep_history: Duration::from_secs(3600),
+            tiers: Vec::new(),
+            interval: slow,
+            burst: Some(Burst {
+                interval: fast,
+                threshold_mb: 10,
+            }),
+            filter: Filter::default(),
+            backend: Backend::Native,
+            capture_env: Vec::new(),
+            max_history_memory: None,
+            data_dir: None,
+        };
+        let mut pacer = Pacer::default();
+        let processes = ScriptedSample::new(0)
+            .with_process(1, 0, "p1", 0)
+            .with_process(2, 1, "p2", 0);
+        let mut next = |interval_ms, memory: &[(u32, u64)]| {
+            let mut sample = processes.at(0, memory).into_sample();
+            sample.interval_ms = interval_ms;
+            pacer.next_interval(&settings, &sample)
+        };
+        assert_eq!(next(None, &[(1, 1000), (2, 1000)]), slow);
+        assert_eq!(next(Some(5000), &[(1, 1020), (2, 1000)]), slow);
+        // total barely changes, but a single process grows fast
+        assert_eq!(next(Some(5000), &[(1, 1200), (2, 810)]), fast);
+        // keep bursting while growing
+        assert_eq!(next(Some(250), &[(1, 1210), (2, 810)]), fast);
+        // a process exiting drops the total fast, but it is not a reason to calm down yet
+        assert_eq!(next(Some(250), &[(1, 1210)]), fast);
+        assert_eq!(next(Some(250), &[(1, 1211)]), slow);
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::process::{CgroupMemory, HistoryEntry, Process};
+
+/// Something notable that happened between two iterations.
+#[derive(Clone, PartialEq)]
+pub enum Event {
+    /// The OOM killer was triggered, either by a cgroup hitting its `memory.max`, or by
+    /// the whole system running out of memory (`cgroup` is `None` then). The victim is guessed
+    /// as the biggest process that disappeared around that time, and may be unknown.
+    OomKill {
+        cgroup: Option<String>,
+        victim: Option<Arc<Process>>,
+        /// Memory of the victim when it was last seen.
+        memory_mb: Option<u64>,
+    },
+    /// A process was seen for the first time.
+    Started {
+        process: Arc<Process>,
+        memory_mb: u64,
+    },
+    /// A process seen in the previous iteration is gone.
+    Exited {
+        process: Arc<Process>,
+        /// Memory of the process when it was last seen.
+        memory_mb: u64,
+    },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::OomKill { .. } => "oom kill",
+            Event::Started { .. } => "started",
+            Event::Exited { .. } => "exited",
+        }
+    }
+
+    /// The process this event is about, if known.
+    pub fn process(&self) -> Option<&Arc<Process>> {
+        match self {
+            Event::OomKill { victim, .. } => victim.as_ref(),
+            Event::Started { process, .. } | Event::Exited { process, .. } => Some(process),
+        }
+    }
+
+    pub fn memory_mb(&self) -> Option<u64> {
+        match self {
+            Event::OomKill { memory_mb, .. } => *memory_mb,
+            Event::Started { memory_mb, .. } | Event::Exited { memory_mb, .. } => Some(*memory_mb),
+        }
+    }
+
+    pub fn cgroup(&self) -> Option<&str> {
+        match self {
+            Event::OomKill { cgroup, .. } => cgroup.as_deref(),
+            Event::Started { process, .. } | Event::Exited { process, .. } => {
+                process.cgroup.as_deref()
+            }
+        }
+    }
+}
+
+/// Events for processes that appeared or disappeared between two consecutive iterations.
+/// A process is the same as long as its pid and start time are, even if it changes
+/// its command line by executing something else.
+pub fn lifecycle(previous: &[HistoryEntry], current: &[HistoryEntry]) -> Vec<Event> {
+    let key = |e: &HistoryEntry| (e.process.pid, e.process.start_time);
+    let before: HashSet<_> = previous.iter().map(key).collect();
+    let after: HashSet<_> = current.iter().map(key).collect();
+    let exited = previous
+        .iter()
+        .filter(|e| !after.contains(&key(e)))
+        .map(|e| Event::Exited {
+            process: e.process.clone(),
+            memory_mb: e.memory_mb,
+        });
+    let started = current
+        .iter()
+        .filter(|e| !before.contains(&key(e)))
+        .map(|e| Event::Started {
+            process: e.process.clone(),
+            memory_mb: e.memory_mb,
+        });
+    exited.chain(started).collect()
+}
+
+/// Watches OOM kill counters between iterations and attributes kills to processes
+/// that disappeared at the same time.
+#[derive(Default)]
+pub struct OomTracker {
+    system_kills: Option<u64>,
+    cgroup_kills: HashMap<String, u64>,
+    /// Processes of the previous iteration with their RSS.
+    previous: HashMap<Arc<Process>, u64>,
+    /// Kills for which no victim was found in the previous iteration. The counter might have
+    /// been increased before the victim actually died, so there is one more chance to find it.
+    pending: Vec<Option<String>>,
+}
+
+impl OomTracker {
+    /// Compare OOM kill counters with the ones from the previous iteration and produce
+    /// an event for every new kill. `system_kills` is the system-wide counter, which also
+    /// includes kills inside cgroups.
+    pub fn update(
+        &mut self,
+        entries: &[HistoryEntry],
+        cgroups: &[CgroupMemory],
+        system_kills: Option<u64>,
+    ) -> Vec<Event> {
+        let mut kills: Vec<Option<String>> = Vec::new();
+        let mut cgroup_kills = HashMap::with_capacity(cgroups.len());
+        for cgroup in cgroups {
+            let Some(events) = cgroup.events else {
+                continue;
+            };
+            if let Some(&previous) = self.cgroup_kills.get(&cgroup.path) {
+                let new = events.oom_kill.saturating_sub(previous);
+                kills.extend((0..new).map(|_| Some(cgroup.path.clone())));
+            }
+            cgroup_kills.insert(cgroup.path.clone(), events.oom_kill);
+        }
+        if let (Some(previous), Some(current)) = (self.system_kills, system_kills) {
+            // kills inside cgroups are counted system-wide as well
+            let new = current.saturating_sub(previous) as usize;
+            let outside_cgroups = new.saturating_sub(kills.len());
+            kills.extend((0..outside_cgroups).map(|_| None));
+        }
+        self.cgroup_kills = cgroup_kills;
+        self.system_kills = system_kills;
+
+        let current: HashSet<&Arc<Process>> = entries.iter().map(|e| &e.process).collect();
+        let mut disappeared: Vec<(&Arc<Process>, u64)> = self
+            .previous
+            .iter()
+            .filter(|(p, _)| !current.contains(p))
+            .map(|(p, &m)| (p, m))
+            .collect();
+        // OOM killer picks the biggest process, so should we
+        disappeared.sort_by_key(|(_, memory)| std::cmp::Reverse(*memory));
+
+        let mut events = Vec::new();
+        let mut still_pending = Vec::new();
+        let pending = std::mem::take(&mut self.pending);
+        let retried = pending.len();
+        for (n, cgroup) in pending.into_iter().chain(kills).enumerate() {
+            let found = disappeared.iter().position(|(p, _)| match &cgroup {
+                None => true,
+                Some(c) => p
+                    .cgroup
+                    .as_deref()
+                    .is_some_and(|pc| is_same_or_descendant(pc, c)),
+            });
+            match found {
+                Some(i) => {
+                    let (victim, memory) = disappeared.remove(i);
+                    events.push(Event::OomKill {
+                        cgroup,
+                        victim: Some(victim.clone()),
+                        memory_mb: Some(memory),
+                    });
+                }
+                None if n < retried => events.push(Event::OomKill {
+                    cgroup,
+                    victim: None,
+                    memory_mb: None,
+                }),
+                None => still_pending.push(cgroup),
+            }
+        }
+        self.pending = still_pending;
+        self.previous = entries
+            .iter()
+            .map(|e| (e.process.clone(), e.memory_mb))
+            .collect();
+        events
+    }
+}
+
+fn is_same_or_descendant(cgroup: &str, ancestor: &str) -> bool {
+    let ancestor = ancestor.trim_end_matches('/');
+    cgroup == ancestor
+        || cgroup
+            .strip_prefix(ancestor)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::MemoryEvents;
+    use crate::source::ScriptedSample;
+
+    /// Entries of processes given as `(pid, cgroup, memory)`.
+    fn entries(processes: &[(u32, &str, u64)]) -> Vec<HistoryEntry> {
+        let mut sample = ScriptedSample::new(0);
+        for &(pid, cgroup, memory_mb) in processes {
+            sample = sample
+                .with_process(pid, 1, &format!("p{pid}"), memory_mb)
+                .in_cgroup(cgroup);
+        }
+        sample.into_sample().entries
+    }
+
+    fn cgroup(path: &str, oom_kill: u64) -> CgroupMemory {
+        CgroupMemory {
+            path: path.to_string(),
+            current_mb: None,
+            max_mb: None,
+            peak_mb: None,
+            events: Some(MemoryEvents {
+                oom_kill,
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn victims(events: &[Event]) -> Vec<(Option<&str>, Option<u32>)> {
+        events
+            .iter()
+            .map(|e| (e.cgroup(), e.process().map(|v| v.pid)))
+            .collect()
+    }
+
+    #[test]
+    fn kill_in_cgroup() {
+        let mut tracker = OomTracker::default();
+        let first = entries(&[
+            (10, "/build", 500),
+            (11, "/build", 100),
+            (20, "/desktop", 900),
+        ]);
+        let cgroups = [cgroup("/build", 3), cgroup("/desktop", 0)];
+        assert!(tracker.update(&first, &cgroups, Some(7)).is_empty());
+
+        // the biggest one disappeared as well, but it was not in the cgroup that hit the limit
+        let second = vec![first[1].clone()];
+        let cgroups = [cgroup("/build", 4)];
+        let events = tracker.update(&second, &cgroups, Some(8));
+        assert_eq!(victims(&events), [(Some("/build"), Some(10))]);
+    }
+
+    #[test]
+    fn victim_disappears_later() {
+        let mut tracker = OomTracker::default();
+        let entries = entries(&[(10, "/", 500), (11, "/", 100)]);
+        assert!(tracker.update(&entries, &[], Some(0)).is_empty());
+        // counter already increased, but the victim is still around
+        assert!(tracker.update(&entries, &[], Some(1)).is_empty());
+        let events = tracker.update(&entries[1..], &[], Some(1));
+        assert_eq!(victims(&events), [(None, Some(10))]);
+        // nobody disappears at all
+        assert!(tracker.update(&entries[1..], &[], Some(2)).is_empty());
+        let events = tracker.update(&entries[1..], &[], Some(2));
+        assert_eq!(victims(&events), [(None, None)]);
+    }
+
+    #[test]
+    fn started_and_exited() {
+        let first = entries(&[(10, "/", 500), (11, "/", 100)]);
+        let mut second = entries(&[(11, "/", 100), (12, "/", 50)]);
+        // same pid and start time, but a different command line after exec()
+        Arc::get_mut(&mut second[0].process).unwrap().argv = vec!["exec'd".into()];
+        let events: Vec<_> = lifecycle(&first, &second)
+            .iter()
+            .map(|e| (e.name(), e.process().unwrap().pid, e.memory_mb()))
+            .collect();
+        assert_eq!(
+            events,
+            [("exited", 10, Some(500)), ("started", 12, Some(50))]
+        );
+    }
+}
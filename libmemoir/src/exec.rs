@@ -0,0 +1,377 @@
+use std::collections::{HashMap, HashSet};
+use std::process::{Child, Command};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::history::History;
+use crate::process::{list_processes, MemoryMetric, Process, ProcessCache};
+use crate::source::Backend;
+use crate::tree::Family;
+
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const TOP_CONSUMERS: usize = 5;
+
+pub struct ChildExit {
+    /// Exit code of the child, or 128 + signal number if it was killed, like shells do.
+    pub code: i32,
+    /// Peak RSS of the largest process that was waited for, as accounted by the kernel: the
+    /// child, descendants the child waited for, and orphans reaped while it was running.
+    pub max_rss_mb: Option<u64>,
+}
+
+/// Result of running a single command under observation.
+pub struct Profile {
+    pub history: History,
+    pub exit: ChildExit,
+    pub duration: Duration,
+}
+
+/// Spawn `command` and sample memory of it and all of its descendants until it exits.
+pub fn profile_command(
+    command: &[String],
+    interval: Duration,
+    backend: Backend,
+    capture_env: &[String],
+) -> Result<Profile> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| anyhow!("No command to execute"))?;
+    // on Linux, orphaned grandchildren get reparented to us instead of init until we return,
+    // so we can reap them; they stay in the subtree if they were listed before their parent
+    // exited
+    let subreaper = platform_specific::Subreaper::become_one();
+
+    let start = Instant::now();
+    let mut child = Command::new(program)
+        .args(args)
+        .spawn()
+        .context(format!("Could not spawn {program:?}"))?;
+    // Ctrl+C is sent to the whole process group; let the child decide what to do with it,
+    // and write the report for whatever was collected
+    let _interrupts = platform_specific::IgnoredInterrupts::new();
+
+    let mut source = crate::source::open(backend)?;
+    let mut cache = ProcessCache::with_capacity(1000).with_captured_env(capture_env.to_vec());
+    let mut history = History::new();
+    // descendants of the child listed in the previous sample, including orphans adopted by
+    // us, which are not in the subtree of the child anymore
+    let mut descendants = HashSet::new();
+    let mut orphans_max_rss_mb = None;
+    let mut exit = loop {
+        let mut sample = list_processes(&mut *source, &mut cache)?;
+        if subreaper.is_set() {
+            // orphans that exited just before being listed are not in the sample anymore
+            let max_rss_mb = platform_specific::reap_orphans(&child, &descendants);
+            orphans_max_rss_mb = orphans_max_rss_mb.max(max_rss_mb);
+        }
+        let family = Family::new(
+            sample
+                .entries
+                .iter()
+                .map(|e| (e.process.pid, e.process.ppid)),
+        );
+        let listed: HashSet<u32> = sample.entries.iter().map(|e| e.process.pid).collect();
+        descendants = std::iter::once(child.id())
+            .chain(descendants)
+            .flat_map(|pid| family.descendants_of(pid))
+            .filter(|pid| listed.contains(pid))
+            .collect();
+        sample
+            .entries
+            .retain(|e| descendants.contains(&e.process.pid));
+        history.push(sample);
+        cache.cleanup();
+
+        let next_sample = Instant::now() + interval;
+        let exit = loop {
+            if subreaper.is_set() {
+                let max_rss_mb = platform_specific::reap_orphans(&child, &descendants);
+                orphans_max_rss_mb = orphans_max_rss_mb.max(max_rss_mb);
+            }
+            if let Some(exit) = platform_specific::try_wait(&mut child)? {
+                break Some(exit);
+            }
+            if Instant::now() >= next_sample {
+                break None;
+            }
+            std::thread::sleep(EXIT_POLL_INTERVAL);
+        };
+        if let Some(exit) = exit {
+            break exit;
+        }
+    };
+    exit.max_rss_mb = exit.max_rss_mb.max(orphans_max_rss_mb);
+    Ok(Profile {
+        history,
+        exit,
+        duration: start.elapsed(),
+    })
+}
+
+impl Profile {
+    /// Print exit status, peak total memory and processes that used the most memory.
+    pub fn print_summary(&self, metric: MemoryMetric) {
+        eprintln!(
+            "Command exited with code {} after {:.1} s, {} samples collected",
+            self.exit.code,
+            self.duration.as_secs_f32(),
+            self.history.len(),
+        );
+        let start = self.history.front_timestamp().unwrap_or_default();
+        // samples with memory of some process unknown are left out, their total would be off
+        let peak = self
+            .history
+            .iter()
+            .filter_map(|h| {
+                let total: Option<u64> = h.entries.iter().map(|e| e.memory(metric)).sum();
+                total.map(|total| (total, h.timestamp))
+            })
+            .max_by_key(|(total, _)| *total);
+        if let Some((total, timestamp)) = peak {
+            eprintln!(
+                "Peak total memory: {total} MB at +{:.1} s",
+                (timestamp - start) as f32 / 1000.
+            );
+        }
+        if let Some(max_rss) = self.exit.max_rss_mb {
+            eprintln!(
+                "Peak RSS of the largest process waited for, according to the kernel: {max_rss} MB"
+            );
+        }
+
+        let mut peaks: HashMap<Arc<Process>, u64> = HashMap::new();
+        for entry in self.history.iter().flat_map(|h| h.entries) {
+            let Some(memory) = entry.memory(metric) else {
+                continue;
+            };
+            let peak = peaks.entry(entry.process.clone()).or_default();
+            *peak = (*peak).max(memory);
+        }
+        let mut peaks: Vec<_> = peaks.into_iter().collect();
+        peaks.sort_by_key(|(_, memory)| std::cmp::Reverse(*memory));
+        if !peaks.is_empty() {
+            eprintln!("Top memory consumers:");
+        }
+        for (process, memory) in peaks.iter().take(TOP_CONSUMERS) {
+            let commandline: String = process.commandline().chars().take(100).collect();
+            eprintln!("  {memory:>8} MB  {:>7}  {commandline}", process.pid);
+        }
+    }
+}
+
+#[cfg(unix)]
+mod platform_specific {
+    use super::*;
+
+    /// Makes this process adopt orphaned descendants for as long as it is alive.
+    pub struct Subreaper {
+        set: bool,
+        /// The process was a subreaper already, and should stay one.
+        was_set: bool,
+    }
+
+    impl Subreaper {
+        #[cfg(target_os = "linux")]
+        pub fn become_one() -> Self {
+            let mut was_set: libc::c_int = 0;
+            // SAFETY: prctl with PR_GET_CHILD_SUBREAPER writes a single int to the pointer,
+            // and with PR_SET_CHILD_SUBREAPER only changes an attribute of this process
+            unsafe {
+                libc::prctl(
+                    libc::PR_GET_CHILD_SUBREAPER,
+                    &mut was_set as *mut libc::c_int,
+                );
+                Subreaper {
+                    set: libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) == 0,
+                    was_set: was_set != 0,
+                }
+            }
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        pub fn become_one() -> Self {
+            Subreaper {
+                set: false,
+                was_set: false,
+            }
+        }
+
+        pub fn is_set(&self) -> bool {
+            self.set
+        }
+    }
+
+    impl Drop for Subreaper {
+        fn drop(&mut self) {
+            if self.set && !self.was_set {
+                // SAFETY: see `become_one`
+                #[cfg(target_os = "linux")]
+                unsafe {
+                    libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 0, 0, 0, 0)
+                };
+            }
+        }
+    }
+
+    /// Ignores SIGINT and SIGQUIT for as long as it is alive, then restores what was there.
+    pub struct IgnoredInterrupts {
+        int: libc::sighandler_t,
+        quit: libc::sighandler_t,
+    }
+
+    impl IgnoredInterrupts {
+        pub fn new() -> Self {
+            // SAFETY: setting disposition to SIG_IGN does not involve any handler code
+            unsafe {
+                IgnoredInterrupts {
+                    int: libc::signal(libc::SIGINT, libc::SIG_IGN),
+                    quit: libc::signal(libc::SIGQUIT, libc::SIG_IGN),
+                }
+            }
+        }
+    }
+
+    impl Drop for IgnoredInterrupts {
+        fn drop(&mut self) {
+            // SAFETY: these are the dispositions signal() returned, valid as they were before
+            unsafe {
+                libc::signal(libc::SIGINT, self.int);
+                libc::signal(libc::SIGQUIT, self.quit);
+            }
+        }
+    }
+
+    /// Reap the child if it has exited, together with its resource usage. `Child::try_wait`
+    /// cannot be used for this, as it does not give rusage back.
+    pub fn try_wait(child: &mut Child) -> Result<Option<ChildExit>> {
+        wait(child.id()).context("Could not wait for child")
+    }
+
+    /// Reap `descendants` of the child that exited after being adopted by us as
+    /// a subreaper, so they do not stay zombies until the child exits. Other children of this
+    /// process are left alone, whoever spawned them waits for them. Returns peak RSS of the
+    /// largest reaped process.
+    pub fn reap_orphans(child: &Child, descendants: &HashSet<u32>) -> Option<u64> {
+        descendants
+            .iter()
+            .filter(|&&pid| pid != child.id())
+            // fails harmlessly for processes that are not our children
+            .filter_map(|&pid| wait(pid).ok().flatten())
+            .filter_map(|exit| exit.max_rss_mb)
+            .max()
+    }
+
+    fn wait(pid: u32) -> std::io::Result<Option<ChildExit>> {
+        let mut status: libc::c_int = 0;
+        // SAFETY: rusage is a plain C struct, for which all-zeroes is a valid value
+        let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+        // SAFETY: pointers are valid for the duration of the call
+        let pid =
+            unsafe { libc::wait4(pid as libc::pid_t, &mut status, libc::WNOHANG, &mut rusage) };
+        match pid {
+            0 => Ok(None),
+            -1 => Err(std::io::Error::last_os_error()),
+            _ => {
+                let code = if libc::WIFEXITED(status) {
+                    libc::WEXITSTATUS(status)
+                } else {
+                    128 + libc::WTERMSIG(status)
+                };
+                // ru_maxrss is in kilobytes on Linux, but in bytes on macOS
+                let bytes = if cfg!(target_os = "macos") { 1 } else { 1024 };
+                Ok(Some(ChildExit {
+                    code,
+                    max_rss_mb: Some(rusage.ru_maxrss as u64 * bytes / 1_000_000),
+                }))
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod platform_specific {
+    use super::*;
+
+    pub struct Subreaper;
+
+    impl Subreaper {
+        pub fn become_one() -> Self {
+            Subreaper
+        }
+
+        pub fn is_set(&self) -> bool {
+            false
+        }
+    }
+
+    pub struct IgnoredInterrupts;
+
+    impl IgnoredInterrupts {
+        pub fn new() -> Self {
+            IgnoredInterrupts
+        }
+    }
+
+    pub fn reap_orphans(_child: &Child, _descendants: &HashSet<u32>) -> Option<u64> {
+        None
+    }
+
+    pub fn try_wait(child: &mut Child) -> Result<Option<ChildExit>> {
+        let status = child.try_wait().context("Could not wait for child")?;
+        Ok(status.map(|s| ChildExit {
+            code: s.code().unwrap_or(1),
+            max_rss_mb: None,
+        }))
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reaps_orphans() {
+        // the subshell exits soon after being sampled, leaving `sleep` to be adopted by us
+        let command = ["sh", "-c", "(sleep 0.2 & sleep 0.1); sleep 0.5; exit 3"].map(String::from);
+        let profile =
+            profile_command(&command, Duration::from_millis(20), Backend::Native, &[]).unwrap();
+        assert_eq!(profile.exit.code, 3);
+        assert!(profile.exit.max_rss_mb.is_some());
+
+        let entries: Vec<_> = profile.history.iter().flat_map(|h| h.entries).collect();
+        let named = |argv: &[&str]| {
+            entries
+                .iter()
+                .find(|e| e.process.argv == argv)
+                .map(|e| e.process.clone())
+        };
+        assert!(named(&["sh", "-c", &command[2]]).is_some());
+        let orphan = named(&["sleep", "0.2"]).unwrap();
+        // SAFETY: a null status pointer is allowed
+        let waited = unsafe {
+            libc::waitpid(
+                orphan.pid as libc::pid_t,
+                std::ptr::null_mut(),
+                libc::WNOHANG,
+            )
+        };
+        assert_eq!(waited, -1, "orphan was not reaped while sampling");
+
+        let mut subreaper: libc::c_int = 1;
+        // SAFETY: PR_GET_CHILD_SUBREAPER writes a single int to the pointer
+        unsafe {
+            libc::prctl(
+                libc::PR_GET_CHILD_SUBREAPER,
+                &mut subreaper as *mut libc::c_int,
+            )
+        };
+        assert_eq!(subreaper, 0);
+        // SAFETY: sigaction is a plain C struct, for which all-zeroes is a valid value
+        let mut interrupt: libc::sigaction = unsafe { std::mem::zeroed() };
+        // SAFETY: a null new action only queries the current one
+        unsafe { libc::sigaction(libc::SIGINT, std::ptr::null(), &mut interrupt) };
+        assert_eq!(interrupt.sa_sigaction, libc::SIG_DFL);
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashSet;
+
+use regex::Regex;
+
+use crate::process::Process;
+use crate::tree::Family;
+
+/// Which processes get recorded at all. Checked while processes are listed, so the ones
+/// filtered out do not take any space in the history.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    /// Record only processes whose executable or command line matches any of these.
+    pub include: Vec<Regex>,
+    /// Skip processes whose executable or command line matches any of these.
+    pub exclude: Vec<Regex>,
+    /// Record only processes of these users.
+    pub uids: Vec<u32>,
+    /// Skip processes with less RSS than that.
+    pub min_memory_mb: u64,
+    /// Record only this process and its descendants.
+    pub descendants_of: Option<u32>,
+}
+
+impl Filter {
+    /// Command line arguments for `memoirctl run` to apply the same filter.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        // regexes may start with a dash, and would be taken for an option on their own
+        for r in &self.include {
+            args.push(format!("--include={}", r.as_str()));
+        }
+        for r in &self.exclude {
+            args.push(format!("--exclude={}", r.as_str()));
+        }
+        for uid in &self.uids {
+            args.extend(["--uid".to_string(), uid.to_string()]);
+        }
+        if self.min_memory_mb > 0 {
+            args.extend(["--min-memory".to_string(), self.min_memory_mb.to_string()]);
+        }
+        if let Some(pid) = self.descendants_of {
+            args.extend(["--descendants-of".to_string(), pid.to_string()]);
+        }
+        args
+    }
+
+    pub fn accepts_memory(&self, memory_mb: u64) -> bool {
+        memory_mb >= self.min_memory_mb
+    }
+
+    /// A process of unknown owner is rejected if any users are given.
+    pub fn accepts_uid(&self, uid: Option<u32>) -> bool {
+        self.uids.is_empty() || uid.is_some_and(|uid| self.uids.contains(&uid))
+    }
+
+    pub fn accepts_names(&self, executable: &str, commandline: &str) -> bool {
+        let matches = |r: &Regex| r.is_match(executable) || r.is_match(commandline);
+        (self.include.is_empty() || self.include.iter().any(matches))
+            && !self.exclude.iter().any(matches)
+    }
+
+    /// Executable is matched as a plain path, command line as quoted by
+    /// `Process::commandline`.
+    pub fn accepts_process(&self, process: &Process) -> bool {
+        let exe = match &process.exe {
+            Some(exe) => exe.to_string_lossy(),
+            None => "?".into(),
+        };
+        self.accepts_names(&exe, &process.commandline())
+    }
+
+    /// Pids that pass `descendants_of`, given parent of every process as `(pid, ppid)`.
+    /// `None` if any pid passes.
+    pub fn subtree(&self, parents: impl Iterator<Item = (u32, u32)>) -> Option<HashSet<u32>> {
+        let root = self.descendants_of?;
+        Some(Family::new(parents).descendants_of(root))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        let filter = Filter {
+            include: vec![Regex::new("cc1plus|ninja").unwrap()],
+            exclude: vec![Regex::new("-E ").unwrap()],
+            ..Default::default()
+        };
+        assert!(filter.accepts_names("/usr/bin/ninja", "ninja -j8"));
+        assert!(filter.accepts_names("/usr/lib/gcc/cc1plus", "cc1plus main.cpp"));
+        assert!(!filter.accepts_names("/usr/lib/gcc/cc1plus", "cc1plus -E main.cpp"));
+        assert!(!filter.accepts_names("/usr/bin/firefox", "firefox"));
+        assert!(Filter::default().accepts_names("/usr/bin/firefox", "firefox"));
+    }
+
+    #[test]
+    fn subtree() {
+        let parents = [
+            (1, 0),
+            (100, 1),
+            (101, 100),
+            (102, 101),
+            (200, 1),
+            (300, 301),
+            (301, 300),
+        ];
+        assert_eq!(Filter::default().subtree(parents.into_iter()), None);
+        let filter = Filter {
+            descendants_of: Some(100),
+            ..Default::default()
+        };
+        let mut pids: Vec<u32> = filter
+            .subtree(parents.into_iter())
+            .unwrap()
+            .into_iter()
+            .collect();
+        pids.sort();
+        assert_eq!(pids, [100, 101, 102]);
+    }
+}
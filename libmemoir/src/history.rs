@@ -0,0 +1,501 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem::size_of;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::events::Event;
+use crate::process::{
+    CgroupMemory, CurrentProcesses, HistoryEntry, IoRates, Pressure, Process, Spread, SystemMemory,
+};
+
+/// Samples collected over time, stored compactly: most processes use about the same memory
+/// from one sample to the next, so only what changed since the previous sample is kept.
+///
+/// Every sample stores which processes were added and removed since the previous one, and
+/// values only of processes whose values changed. The oldest sample is a keyframe with
+/// everything in it, so the history can be read front to back and trimmed at the front.
+#[derive(Default, Clone)]
+pub struct History {
+    frames: VecDeque<Frame>,
+    /// Sequence number of the front frame; every frame gets the next one.
+    front_seq: u64,
+    processes: HashMap<u32, Slot>,
+    /// Processes are shared between samples by `ProcessCache`, so they are told apart by
+    /// address, which stays taken while the process is in a slot.
+    ids: HashMap<usize, u32>,
+    next_id: u32,
+    /// Processes of the back frame, to compute the difference with the next one.
+    last: State,
+    /// Memory taken by frames outside of the structures themselves.
+    heap_bytes: usize,
+    /// Memory taken by processes in slots.
+    process_bytes: usize,
+}
+
+/// A process referenced from frames by a small id instead of a pointer.
+#[derive(Clone)]
+struct Slot {
+    process: Arc<Process>,
+    /// Sequence number of the last frame the process is in.
+    last_seq: u64,
+}
+
+/// Everything about a process in a sample, except the process itself.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Values {
+    memory_mb: u64,
+    peak_mb: Option<u64>,
+    pss_mb: Option<u64>,
+    uss_mb: Option<u64>,
+    swap_mb: Option<u64>,
+    cpu_percent: Option<f32>,
+    io: Option<IoRates>,
+    state: Option<char>,
+    spread: Option<Spread>,
+}
+
+impl Values {
+    pub(crate) fn of(entry: &HistoryEntry) -> Self {
+        Values {
+            memory_mb: entry.memory_mb,
+            peak_mb: entry.peak_mb,
+            pss_mb: entry.pss_mb,
+            uss_mb: entry.uss_mb,
+            swap_mb: entry.swap_mb,
+            cpu_percent: entry.cpu_percent,
+            io: entry.io,
+            state: entry.state,
+            spread: entry.spread,
+        }
+    }
+
+    pub(crate) fn entry(&self, process: Arc<Process>) -> HistoryEntry {
+        HistoryEntry {
+            process,
+            memory_mb: self.memory_mb,
+            peak_mb: self.peak_mb,
+            pss_mb: self.pss_mb,
+            uss_mb: self.uss_mb,
+            swap_mb: self.swap_mb,
+            cpu_percent: self.cpu_percent,
+            io: self.io,
+            state: self.state,
+            spread: self.spread,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Frame {
+    timestamp: u128,
+    interval_ms: Option<u64>,
+    members: Members,
+    /// Processes whose values differ from the previous frame, or which were not in it.
+    changes: Vec<(u32, Values)>,
+    /// Shared with the previous frame while unchanged.
+    system: Option<Arc<SystemMemory>>,
+    pressure: Option<Pressure>,
+    /// Shared with the previous frame while unchanged.
+    cgroups: Arc<[CgroupMemory]>,
+    events: Vec<Event>,
+    /// Memory taken by the frame outside of it, not counting what is shared with the previous one.
+    heap_bytes: usize,
+}
+
+impl Frame {
+    fn heap_bytes(&self, shares_system: bool, shares_cgroups: bool) -> usize {
+        let members = match &self.members {
+            Members::Same => 0,
+            Members::Edit { removed, added } => {
+                removed.capacity() * size_of::<u32>() + added.capacity() * size_of::<(u32, u32)>()
+            }
+            Members::Full(order) => order.capacity() * size_of::<u32>(),
+        };
+        let system = if self.system.is_some() && !shares_system {
+            size_of::<SystemMemory>()
+        } else {
+            0
+        };
+        let cgroups = if shares_cgroups {
+            0
+        } else {
+            self.cgroups
+                .iter()
+                .map(|c| size_of::<CgroupMemory>() + c.path.len())
+                .sum()
+        };
+        members
+            + self.changes.capacity() * size_of::<(u32, Values)>()
+            + system
+            + cgroups
+            + self.events.capacity() * size_of::<Event>()
+    }
+}
+
+/// Which processes are in a frame, and in which order.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) enum Members {
+    /// Same as in the previous frame.
+    Same,
+    /// Some processes of the previous frame are gone, and new ones are inserted at given
+    /// positions, in ascending order of positions.
+    Edit {
+        removed: Vec<u32>,
+        added: Vec<(u32, u32)>,
+    },
+    /// Listed in full, for a keyframe or when the order has changed.
+    Full(Vec<u32>),
+}
+
+/// Processes of a frame in their order, with their values.
+#[derive(Default, Clone)]
+pub(crate) struct State {
+    pub(crate) order: Vec<u32>,
+    pub(crate) values: HashMap<u32, Values>,
+}
+
+impl State {
+    /// Move from the previous frame to the one with given `members` and `changes`.
+    pub(crate) fn apply(&mut self, members: &Members, changes: &[(u32, Values)]) {
+        match members {
+            Members::Same => {}
+            Members::Edit { removed, added } => {
+                let removed: HashSet<u32> = removed.iter().copied().collect();
+                self.order.retain(|id| !removed.contains(id));
+                for id in removed {
+                    self.values.remove(&id);
+                }
+                for &(position, id) in added {
+                    self.order.insert(position as usize, id);
+                }
+            }
+            Members::Full(order) => {
+                self.order = order.clone();
+                let members: HashSet<u32> = order.iter().copied().collect();
+                self.values.retain(|id, _| members.contains(id));
+            }
+        }
+        for (id, values) in changes {
+            self.values.insert(*id, *values);
+        }
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Timestamp of the oldest sample.
+    pub fn front_timestamp(&self) -> Option<u128> {
+        self.frames.front().map(|f| f.timestamp)
+    }
+
+    /// Timestamp of the newest sample.
+    pub fn back_timestamp(&self) -> Option<u128> {
+        self.frames.back().map(|f| f.timestamp)
+    }
+
+    /// Memory taken by the history, roughly.
+    pub fn approx_bytes(&self) -> usize {
+        self.frames_bytes() + self.process_bytes
+    }
+
+    /// Memory taken by the history without the processes, which other histories may share.
+    pub(crate) fn frames_bytes(&self) -> usize {
+        size_of::<Self>()
+            + self.heap_bytes
+            + self.frames.len() * size_of::<Frame>()
+            + map_bytes::<u32, Slot>(self.processes.len())
+            + map_bytes::<usize, u32>(self.ids.len())
+            + self.last.order.len() * size_of::<u32>()
+            + map_bytes::<u32, Values>(self.last.values.len())
+    }
+
+    /// Processes referenced from any sample.
+    pub(crate) fn processes(&self) -> impl Iterator<Item = &Arc<Process>> {
+        self.processes.values().map(|slot| &slot.process)
+    }
+
+    /// Processes of the newest sample.
+    pub fn last_entries(&self) -> Vec<HistoryEntry> {
+        self.last
+            .order
+            .iter()
+            .map(|id| self.last.values[id].entry(self.processes[id].process.clone()))
+            .collect()
+    }
+
+    pub fn push(&mut self, sample: CurrentProcesses) {
+        let seq = self.front_seq + self.frames.len() as u64;
+        let mut order = Vec::with_capacity(sample.entries.len());
+        let mut values = HashMap::with_capacity(sample.entries.len());
+        let mut changes = Vec::new();
+        for entry in &sample.entries {
+            let id = match self.ids.get(&address(&entry.process)) {
+                Some(&id) => id,
+                None => {
+                    let id = self.next_id;
+                    self.next_id = self.next_id.wrapping_add(1);
+                    self.ids.insert(address(&entry.process), id);
+                    self.process_bytes += entry.process.approx_bytes();
+                    self.processes.insert(
+                        id,
+                        Slot {
+                            process: entry.process.clone(),
+                            last_seq: seq,
+                        },
+                    );
+                    id
+                }
+            };
+            self.processes.get_mut(&id).unwrap().last_seq = seq;
+            let current = Values::of(entry);
+            if self.last.values.get(&id) != Some(&current) {
+                changes.push((id, current));
+            }
+            order.push(id);
+            values.insert(id, current);
+        }
+        changes.shrink_to_fit();
+        let members = if self.frames.is_empty() {
+            Members::Full(order.clone())
+        } else {
+            members(&self.last.order, &order)
+        };
+
+        let previous = self.frames.back();
+        let (system, shares_system) =
+            match (sample.system, previous.and_then(|p| p.system.as_ref())) {
+                (Some(system), Some(shared)) if **shared == system => (Some(shared.clone()), true),
+                (system, _) => (system.map(Arc::new), false),
+            };
+        let (cgroups, shares_cgroups) = match previous {
+            Some(p) if *p.cgroups == *sample.cgroups => (p.cgroups.clone(), true),
+            _ => (sample.cgroups.into(), false),
+        };
+        let mut frame = Frame {
+            timestamp: sample.timestamp,
+            interval_ms: sample.interval_ms,
+            members,
+            changes,
+            system,
+            pressure: sample.pressure,
+            cgroups,
+            events: sample.events,
+            heap_bytes: 0,
+        };
+        frame.heap_bytes = frame.heap_bytes(shares_system, shares_cgroups);
+        self.heap_bytes += frame.heap_bytes;
+        self.frames.push_back(frame);
+        self.last = State { order, values };
+    }
+
+    /// Remove the oldest sample, turning the next one into a keyframe.
+    pub fn pop_front(&mut self) -> Option<CurrentProcesses> {
+        let front = self.frames.pop_front()?;
+        self.front_seq += 1;
+        self.heap_bytes -= front.heap_bytes;
+        let mut state = State::default();
+        state.apply(&front.members, &front.changes);
+        let sample = self.decode(&front, &state);
+        if let Some(next) = self.frames.front_mut() {
+            state.apply(&next.members, &next.changes);
+            next.changes = state
+                .order
+                .iter()
+                .map(|id| (*id, state.values[id]))
+                .collect();
+            next.members = Members::Full(state.order);
+            // the front frame owns everything it used to share with the previous one
+            self.heap_bytes -= next.heap_bytes;
+            next.heap_bytes = next.heap_bytes(false, false);
+            self.heap_bytes += next.heap_bytes;
+        } else {
+            self.last = State::default();
+        }
+        let front_seq = self.front_seq;
+        let ids = &mut self.ids;
+        let process_bytes = &mut self.process_bytes;
+        self.processes.retain(|_, slot| {
+            let keep = slot.last_seq >= front_seq;
+            if !keep {
+                ids.remove(&address(&slot.process));
+                *process_bytes -= slot.process.approx_bytes();
+            }
+            keep
+        });
+        Some(sample)
+    }
+
+    /// Remove all samples taken before `timestamp`, returning them.
+    pub fn trim_before(&mut self, timestamp: u128) -> Vec<CurrentProcesses> {
+        let mut removed = Vec::new();
+        while self.front_timestamp().is_some_and(|t| t < timestamp) {
+            removed.extend(self.pop_front());
+        }
+        removed
+    }
+
+    /// Sample of a frame, with processes of the frame in `state`.
+    fn decode(&self, frame: &Frame, state: &State) -> CurrentProcesses {
+        CurrentProcesses {
+            timestamp: frame.timestamp,
+            interval_ms: frame.interval_ms,
+            entries: state
+                .order
+                .iter()
+                .map(|id| state.values[id].entry(self.processes[id].process.clone()))
+                .collect(),
+            system: frame.system.as_deref().cloned(),
+            pressure: frame.pressure.clone(),
+            cgroups: frame.cgroups.to_vec(),
+            events: frame.events.clone(),
+        }
+    }
+
+    /// Samples from the oldest to the newest.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            history: self,
+            frames: self.frames.iter(),
+            state: State::default(),
+        }
+    }
+}
+
+impl FromIterator<CurrentProcesses> for History {
+    fn from_iter<T: IntoIterator<Item = CurrentProcesses>>(samples: T) -> Self {
+        let mut history = History::new();
+        for sample in samples {
+            history.push(sample);
+        }
+        history
+    }
+}
+
+/// Memory taken by a hash map with `len` entries, roughly.
+pub(crate) fn map_bytes<K, V>(len: usize) -> usize {
+    // one control byte per entry, and some free space
+    len * (size_of::<(K, V)>() + 1) * 8 / 7
+}
+
+fn address(process: &Arc<Process>) -> usize {
+    Arc::as_ptr(process) as usize
+}
+
+/// How to get from `previous` order of processes to `current` one.
+pub(crate) fn members(previous: &[u32], current: &[u32]) -> Members {
+    if previous == current {
+        return Members::Same;
+    }
+    let current_set: HashSet<u32> = current.iter().copied().collect();
+    let previous_set: HashSet<u32> = previous.iter().copied().collect();
+    let mut kept = previous.iter().filter(|id| current_set.contains(id));
+    let mut added = Vec::new();
+    for (position, id) in current.iter().enumerate() {
+        if !previous_set.contains(id) {
+            added.push((position as u32, *id));
+        } else if kept.next() != Some(id) {
+            // processes that stayed have changed their order
+            return Members::Full(current.to_vec());
+        }
+    }
+    Members::Edit {
+        removed: previous
+            .iter()
+            .filter(|id| !current_set.contains(id))
+            .copied()
+            .collect(),
+        added,
+    }
+}
+
+/// Decodes samples one by one.
+pub struct Iter<'a> {
+    history: &'a History,
+    frames: std::collections::vec_deque::Iter<'a, Frame>,
+    state: State,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = CurrentProcesses;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let frame = self.frames.next()?;
+        self.state.apply(&frame.members, &frame.changes);
+        Some(self.history.decode(frame, &self.state))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.frames.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::ScriptedSample;
+
+    fn memory(history: &History) -> Vec<Vec<(u32, u64)>> {
+        history
+            .iter()
+            .map(|s| {
+                s.entries
+                    .iter()
+                    .map(|e| (e.process.pid, e.memory_mb))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let samples = vec![
+            vec![(1, 10), (2, 20), (3, 30)],
+            vec![(1, 10), (2, 25), (3, 30)],
+            // 2 exits, 4 and 0 start
+            vec![(0, 5), (1, 10), (3, 30), (4, 40)],
+            vec![(0, 5), (1, 10), (3, 30), (4, 40)],
+            // order changes
+            vec![(4, 41), (3, 30), (1, 10)],
+            vec![],
+            vec![(2, 20)],
+        ];
+        let mut base = ScriptedSample::new(0);
+        for pid in 0..=4 {
+            base = base.with_process(pid, 1, &format!("p{pid}"), 0);
+        }
+        let mut history: History = samples
+            .iter()
+            .enumerate()
+            .map(|(i, s)| base.at(i as u128, s).into_sample())
+            .collect();
+        assert_eq!(memory(&history), samples);
+        assert_eq!(history.last_entries().len(), 1);
+
+        for trimmed in 1..=samples.len() {
+            let front = history.pop_front().unwrap();
+            let front: Vec<(u32, u64)> = front
+                .entries
+                .iter()
+                .map(|e| (e.process.pid, e.memory_mb))
+                .collect();
+            assert_eq!(front, samples[trimmed - 1]);
+            assert_eq!(memory(&history), samples[trimmed..]);
+        }
+        assert!(history.processes.is_empty() && history.ids.is_empty());
+        assert_eq!(history.heap_bytes, 0);
+        history.push(base.at(10, &[(1, 10)]).into_sample());
+        assert_eq!(memory(&history), [[(1, 10)]]);
+    }
+}
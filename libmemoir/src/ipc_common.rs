@@ -2,7 +2,15 @@ use anyhow::Context;
 use interprocess::local_socket::{LocalSocketStream, NameTypeSupport};
 use serde::{Deserialize, Serialize};
 
+use crate::csvdump::ExportOptions;
+use crate::process::MemoryMetric;
+
+/// Name of the socket the daemon listens on; `MEMOIR_SOCKET` environment variable
+/// overrides it, e.g. to run several daemons at once.
 pub fn socket_name() -> String {
+    if let Ok(name) = std::env::var("MEMOIR_SOCKET") {
+        return name;
+    }
     use NameTypeSupport::*;
     match NameTypeSupport::query() {
         OnlyPaths => String::from("/tmp/memoirrs.sock"),
@@ -20,8 +28,22 @@ pub enum Signal {
     Error,
     Stop,
     Ping,
-    Save { to: SaveTo, time_sec: Option<usize> },
-    Output { output: String },
+    Status,
+    Save {
+        to: SaveTo,
+        time_sec: Option<usize>,
+        options: ExportOptions,
+    },
+    Events {
+        time_sec: Option<usize>,
+    },
+    Lifetimes {
+        time_sec: Option<usize>,
+        metric: MemoryMetric,
+    },
+    Output {
+        output: String,
+    },
 }
 impl Signal {
     pub fn feed_into(self, into: &mut LocalSocketStream) -> anyhow::Result<()> {
@@ -30,7 +52,45 @@ impl Signal {
     }
 
     pub fn read_from(from: &mut LocalSocketStream) -> anyhow::Result<Signal> {
-        let reader = std::io::BufReader::new(from);
-        ciborium::from_reader(reader).context("Failed to read signal from socket")
+        // no BufReader here: it would swallow the beginning of the next signal if the peer
+        // sends several in a row, e.g. Ack followed by Output
+        ciborium::from_reader(from).context("Failed to read signal from socket")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interprocess::local_socket::LocalSocketListener;
+
+    #[test]
+    fn signals_in_a_row() {
+        let name = match NameTypeSupport::query() {
+            NameTypeSupport::OnlyPaths => format!("/tmp/memoir-test-{}.sock", std::process::id()),
+            _ => format!("@memoir-test-{}.sock", std::process::id()),
+        };
+        let listener = LocalSocketListener::bind(name.clone()).unwrap();
+        let mut client = LocalSocketStream::connect(name.clone()).unwrap();
+        let mut server = listener.accept().unwrap();
+        // the daemon answers a save request with Ack and then Output right away, so both
+        // usually arrive together
+        Signal::Ack.feed_into(&mut server).unwrap();
+        Signal::Output {
+            output: "report".to_string(),
+        }
+        .feed_into(&mut server)
+        .unwrap();
+        drop(server);
+        drop(listener);
+        let _ = std::fs::remove_file(&name);
+
+        assert!(matches!(
+            Signal::read_from(&mut client).unwrap(),
+            Signal::Ack
+        ));
+        let Signal::Output { output } = Signal::read_from(&mut client).unwrap() else {
+            panic!("Expected output");
+        };
+        assert_eq!(output, "report");
     }
 }
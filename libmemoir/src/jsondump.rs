@@ -0,0 +1,209 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::csvdump::{since, ExportOptions, Selection};
+use crate::events::Event;
+use crate::history::History;
+use crate::process::{
+    CgroupMemory, HistoryEntry, IoRates, MemoryMetric, Pressure, Process, Spread, SystemMemory,
+};
+
+#[derive(Serialize)]
+struct Sample<'a> {
+    iteration: usize,
+    timestamp: u128,
+    interval_ms: Option<u64>,
+    processes: Vec<Entry<'a>>,
+    system: Option<&'a SystemMemory>,
+    pressure: Option<&'a Pressure>,
+    cgroups: &'a [CgroupMemory],
+    events: Vec<JsonEvent<'a>>,
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    #[serde(flatten)]
+    process: JsonProcess<'a>,
+    /// Like `Memory MB` column, according to the metric asked for.
+    memory_mb: Option<u64>,
+    rss_mb: u64,
+    peak_mb: Option<u64>,
+    pss_mb: Option<u64>,
+    uss_mb: Option<u64>,
+    swap_mb: Option<u64>,
+    cpu_percent: Option<f32>,
+    io: Option<IoRates>,
+    state: Option<char>,
+    spread: Option<Spread>,
+}
+
+#[derive(Serialize)]
+struct JsonProcess<'a> {
+    pid: u32,
+    ppid: u32,
+    instance: String,
+    exe: Option<Cow<'a, str>>,
+    argv: Vec<Cow<'a, str>>,
+    cwd: Option<Cow<'a, str>>,
+    uid: Option<u32>,
+    user: Option<&'a str>,
+    env: BTreeMap<&'a str, Cow<'a, str>>,
+    cgroup: Option<&'a str>,
+    start_time: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct JsonEvent<'a> {
+    event: &'static str,
+    process: Option<JsonProcess<'a>>,
+    memory_mb: Option<u64>,
+    /// For OOM kills, the cgroup which hit its limit.
+    cgroup: Option<&'a str>,
+}
+
+pub fn save_to_file(
+    history: &History,
+    destination: &PathBuf,
+    time_sec: Option<usize>,
+    options: &ExportOptions,
+) -> anyhow::Result<()> {
+    let file = File::create(destination).context(format!("Could not create {:?}", destination))?;
+    let mut writer = BufWriter::new(file);
+    save_to_stream(history, &mut writer, time_sec, options)?;
+    writer
+        .flush()
+        .context(format!("Could not write to {:?}", destination))
+}
+
+/// Write an array of samples, one per line, with every value known for every process.
+/// Arguments are written as arrays, and paths and arguments that are not UTF-8 get
+/// the offending bytes replaced.
+pub fn save_to_stream<W: Write>(
+    history: &History,
+    mut writer: W,
+    time_sec: Option<usize>,
+    options: &ExportOptions,
+) -> anyhow::Result<()> {
+    let since = since(time_sec)?;
+    let selection = Selection::new(options)?;
+    let mut separator = "[";
+    for (iteration, processes) in history.iter().enumerate() {
+        if processes.timestamp < since {
+            continue;
+        }
+        let sample = Sample {
+            iteration: iteration + 1,
+            timestamp: processes.timestamp,
+            interval_ms: processes.interval_ms,
+            processes: processes
+                .entries
+                .iter()
+                .filter(|e| selection.accepts(e))
+                .map(|e| Entry::of(e, options.metric))
+                .collect(),
+            system: processes.system.as_ref(),
+            pressure: processes.pressure.as_ref(),
+            cgroups: &processes.cgroups,
+            events: processes.events.iter().map(JsonEvent::of).collect(),
+        };
+        writer.write_all(separator.as_bytes())?;
+        serde_json::to_writer(&mut writer, &sample).context("Could not write sample")?;
+        separator = ",\n";
+    }
+    if separator == "[" {
+        writer.write_all(b"[")?;
+    }
+    writer.write_all(b"]\n")?;
+    Ok(())
+}
+
+impl<'a> Entry<'a> {
+    fn of(entry: &'a HistoryEntry, metric: MemoryMetric) -> Self {
+        Entry {
+            process: JsonProcess::of(&entry.process),
+            memory_mb: entry.memory(metric),
+            rss_mb: entry.memory_mb,
+            peak_mb: entry.peak_mb,
+            pss_mb: entry.pss_mb,
+            uss_mb: entry.uss_mb,
+            swap_mb: entry.swap_mb,
+            cpu_percent: entry.cpu_percent,
+            io: entry.io,
+            state: entry.state,
+            spread: entry.spread,
+        }
+    }
+}
+
+impl<'a> JsonProcess<'a> {
+    fn of(process: &'a Process) -> Self {
+        JsonProcess {
+            pid: process.pid,
+            ppid: process.ppid,
+            instance: process.instance_id(),
+            exe: process.exe.as_ref().map(|p| p.to_string_lossy()),
+            argv: process.argv.iter().map(|a| a.to_string_lossy()).collect(),
+            cwd: process.cwd.as_ref().map(|p| p.to_string_lossy()),
+            uid: process.uid,
+            user: process.user.as_deref(),
+            env: process
+                .env
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.to_string_lossy()))
+                .collect(),
+            cgroup: process.cgroup.as_deref(),
+            start_time: process.start_time,
+        }
+    }
+}
+
+impl<'a> JsonEvent<'a> {
+    fn of(event: &'a Event) -> Self {
+        let cgroup = match event {
+            Event::OomKill { cgroup, .. } => cgroup.as_deref(),
+            _ => None,
+        };
+        JsonEvent {
+            event: event.name(),
+            process: event.process().map(|p| JsonProcess::of(p)),
+            memory_mb: event.memory_mb(),
+            cgroup,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::ScriptedSample;
+    use std::ffi::OsString;
+    use std::sync::Arc;
+
+    #[test]
+    fn argv_as_array() {
+        let mut sample = ScriptedSample::new(1_000).with_process(100, 1, "tool", 20);
+        let tool = Arc::get_mut(&mut sample.processes[0].0.process).unwrap();
+        tool.exe = Some("/opt/my app/tool".into());
+        tool.argv = ["/opt/my app/tool", "-o", "out dir", ""]
+            .map(OsString::from)
+            .to_vec();
+        let history = History::from_iter([sample.into_sample()]);
+
+        let mut out = Vec::new();
+        save_to_stream(&history, &mut out, None, &ExportOptions::default()).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let process = &json[0]["processes"][0];
+        assert_eq!(process["exe"], "/opt/my app/tool");
+        assert_eq!(
+            process["argv"],
+            serde_json::json!(["/opt/my app/tool", "-o", "out dir", ""])
+        );
+        assert_eq!(process["memory_mb"], 20);
+    }
+}
@@ -1,6 +1,24 @@
 pub mod control;
 pub mod daemon;
+pub mod history;
+pub mod native;
+pub mod process;
+pub mod source;
 
 mod csvdump;
+mod events;
+mod exec;
+mod filter;
 mod ipc_common;
-mod process;
+mod jsondump;
+mod quote;
+mod report;
+mod retention;
+mod segments;
+mod stored;
+mod tree;
+
+pub use csvdump::ExportOptions;
+pub use events::Event;
+pub use filter::Filter;
+pub use process::MemoryMetric;
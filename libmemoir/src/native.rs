@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::history::History;
+use crate::process::Process;
+use crate::stored::{os_string, Decoder, Encoder};
+
+/// Written at the start of every file, with the version of the format.
+const MAGIC: &[u8] = b"memoir report 1\n";
+
+/// Start of a `.memoir` file, right after `MAGIC`. The rest of the file is a zlib stream
+/// of CBOR values: the header, and then samples, each relative to the previous one.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    /// Every string of every process, once.
+    strings: Vec<ByteBuf>,
+    /// Processes referred to from samples by their position.
+    processes: Vec<TableProcess>,
+    /// How many samples follow.
+    samples: usize,
+}
+
+/// `Process` with strings given by their position in the string table.
+#[derive(Serialize, Deserialize)]
+struct TableProcess {
+    pid: u32,
+    ppid: u32,
+    exe: Option<u32>,
+    argv: Vec<u32>,
+    cwd: Option<u32>,
+    uid: Option<u32>,
+    user: Option<u32>,
+    env: Vec<(u32, u32)>,
+    cgroup: Option<u32>,
+    start_time: Option<u64>,
+}
+
+#[derive(Default)]
+struct Strings {
+    table: Vec<ByteBuf>,
+    positions: HashMap<Vec<u8>, u32>,
+}
+
+impl Strings {
+    fn add(&mut self, string: &[u8]) -> u32 {
+        if let Some(&position) = self.positions.get(string) {
+            return position;
+        }
+        let position = self.table.len() as u32;
+        self.table.push(ByteBuf::from(string));
+        self.positions.insert(string.to_vec(), position);
+        position
+    }
+}
+
+/// Write samples taken within the last `time_sec` seconds to a `.memoir` file.
+pub fn save_to_file(history: &History, destination: &Path, time_sec: Option<usize>) -> Result<()> {
+    let file = File::create(destination).context(format!("Could not create {:?}", destination))?;
+    let mut writer = BufWriter::new(file);
+    save_to_stream(history, &mut writer, time_sec)?;
+    writer
+        .flush()
+        .context(format!("Could not write to {:?}", destination))
+}
+
+pub fn save_to_stream<W: Write>(
+    history: &History,
+    mut writer: W,
+    time_sec: Option<usize>,
+) -> Result<()> {
+    let since = crate::csvdump::since(time_sec)?;
+    let samples = || history.iter().filter(|s| s.timestamp >= since);
+    // processes have to be known before the samples, so samples are encoded twice, the same
+    // way both times, instead of being kept in memory
+    let mut strings = Strings::default();
+    let mut processes = Vec::new();
+    let mut encoder = Encoder::default();
+    let mut count = 0;
+    for sample in samples() {
+        encoder.encode(&sample, |id, process| {
+            debug_assert_eq!(id as usize, processes.len());
+            processes.push(TableProcess::of(process, &mut strings));
+        });
+        count += 1;
+    }
+    let header = Header {
+        strings: strings.table,
+        processes,
+        samples: count,
+    };
+
+    writer.write_all(MAGIC).context("Could not write header")?;
+    let mut compressed = BufWriter::new(ZlibEncoder::new(writer, Compression::default()));
+    ciborium::into_writer(&header, &mut compressed).context("Could not write header")?;
+    let mut encoder = Encoder::default();
+    for sample in samples() {
+        let sample = encoder.encode(&sample, |_, _| {});
+        ciborium::into_writer(&sample, &mut compressed).context("Could not write sample")?;
+    }
+    compressed
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .and_then(|encoder| encoder.finish())
+        .context("Could not write samples")?;
+    Ok(())
+}
+
+/// Read a `.memoir` file back.
+pub fn read_from_file(source: &Path) -> Result<History> {
+    let file = File::open(source).context(format!("Could not open {:?}", source))?;
+    read_from_stream(BufReader::new(file)).context(format!("Could not read {:?}", source))
+}
+
+pub fn read_from_stream<R: Read>(mut reader: R) -> Result<History> {
+    let mut magic = vec![0; MAGIC.len()];
+    reader
+        .read_exact(&mut magic)
+        .context("Could not read header")?;
+    if magic != MAGIC {
+        return Err(anyhow!("Not a memoir report, or one of an unknown version"));
+    }
+    let mut reader = BufReader::new(ZlibDecoder::new(reader));
+    let header: Header = ciborium::from_reader(&mut reader).context("Could not read header")?;
+    let mut decoder = Decoder::default();
+    for (id, process) in header.processes.into_iter().enumerate() {
+        decoder.add_process(id as u32, Arc::new(process.process(&header.strings)?));
+    }
+    let mut history = History::new();
+    for _ in 0..header.samples {
+        let sample = ciborium::from_reader(&mut reader).context("Could not read sample")?;
+        history.push(decoder.decode(sample)?);
+    }
+    Ok(history)
+}
+
+impl TableProcess {
+    fn of(process: &Process, strings: &mut Strings) -> Self {
+        let exe = process.exe.as_ref();
+        let cwd = process.cwd.as_ref();
+        TableProcess {
+            pid: process.pid,
+            ppid: process.ppid,
+            exe: exe.map(|p| strings.add(p.as_os_str().as_encoded_bytes())),
+            argv: process
+                .argv
+                .iter()
+                .map(|a| strings.add(a.as_encoded_bytes()))
+                .collect(),
+            cwd: cwd.map(|p| strings.add(p.as_os_str().as_encoded_bytes())),
+            uid: process.uid,
+            user: process.user.as_ref().map(|u| strings.add(u.as_bytes())),
+            env: process
+                .env
+                .iter()
+                .map(|(name, value)| {
+                    (
+                        strings.add(name.as_bytes()),
+                        strings.add(value.as_encoded_bytes()),
+                    )
+                })
+                .collect(),
+            cgroup: process.cgroup.as_ref().map(|c| strings.add(c.as_bytes())),
+            start_time: process.start_time,
+        }
+    }
+
+    fn process(self, strings: &[ByteBuf]) -> Result<Process> {
+        let bytes = |position: u32| {
+            strings
+                .get(position as usize)
+                .cloned()
+                .ok_or_else(|| anyhow!("String #{position} is not in the table"))
+        };
+        let text = |position: u32| -> Result<String> {
+            Ok(String::from_utf8_lossy(&bytes(position)?).into_owned())
+        };
+        let os = |position: u32| -> Result<OsString> { Ok(os_string(bytes(position)?)) };
+        Ok(Process {
+            pid: self.pid,
+            ppid: self.ppid,
+            exe: self.exe.map(os).transpose()?.map(Into::into),
+            argv: self.argv.into_iter().map(os).collect::<Result<_>>()?,
+            cwd: self.cwd.map(os).transpose()?.map(Into::into),
+            uid: self.uid,
+            user: self.user.map(text).transpose()?,
+            env: self
+                .env
+                .into_iter()
+                .map(|(name, value)| Ok((text(name)?, os(value)?)))
+                .collect::<Result<_>>()?,
+            cgroup: self.cgroup.map(text).transpose()?,
+            start_time: self.start_time,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csvdump::{self, ExportOptions};
+    use crate::process::{list_processes, ProcessCache};
+    use crate::source::{ScriptedSample, ScriptedSource};
+    use std::collections::HashSet;
+
+    #[test]
+    fn round_trip() {
+        let script = (0..100).map(|i| {
+            let mut sample = ScriptedSample::new(i * 1000)
+                .with_process(1, 0, "/sbin/init", 10)
+                .with_process(100, 1, "make -j8 'all targets'", 20 + i as u64 % 3);
+            let make = Arc::get_mut(&mut sample.processes[1].0.process).unwrap();
+            make.env.push(("CI_JOB_ID".to_string(), "42".into()));
+            make.cwd = Some("/src/my project".into());
+            if i % 10 < 5 {
+                sample = sample.with_process(100 + i as u32, 100, "cc1plus main.cpp", 300);
+            }
+            if i % 20 != 10 {
+                sample = sample.with_process(50, 1, "sshd", 5);
+            }
+            sample
+        });
+        let mut source = ScriptedSource::new(script);
+        let mut cache = ProcessCache::default();
+        let history: History = (0..100)
+            .map(|_| list_processes(&mut source, &mut cache).unwrap())
+            .collect();
+
+        let mut native = Vec::new();
+        save_to_stream(&history, &mut native, None).unwrap();
+        let read = read_from_stream(native.as_slice()).unwrap();
+        let options = ExportOptions {
+            details: true,
+            ..Default::default()
+        };
+        let csv = |history: &History| {
+            let mut out = Vec::new();
+            csvdump::save_to_stream(history, &mut out, None, &options).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(csv(&read), csv(&history));
+        // a process missing from some samples is still written once
+        let processes = |history: &History| {
+            let entries: Vec<_> = history.iter().flat_map(|s| s.entries).collect();
+            let addresses: HashSet<_> = entries.iter().map(|e| Arc::as_ptr(&e.process)).collect();
+            addresses.len()
+        };
+        assert_eq!(processes(&read), processes(&history));
+        assert!(native.len() * 10 < csv(&history).len());
+
+        assert!(read_from_stream(&b"Iteration\tTimestamp\n"[..]).is_err());
+        assert!(read_from_stream(&native[..native.len() - 10]).is_err());
+    }
+}
@@ -1,17 +1,238 @@
-use std::{collections::HashSet, sync::Arc, vec::Vec};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    hash::{Hash, Hasher},
+    mem::size_of,
+    path::PathBuf,
+    sync::Arc,
+    vec::Vec,
+};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
 
-#[derive(Eq, Hash, PartialEq)]
+use crate::events::{Event, OomTracker};
+use crate::filter::Filter;
+use crate::history::map_bytes;
+use crate::quote;
+use crate::source::ProcessSource;
+
+/// A process as it was first seen. Everything that may change while it runs without making it
+/// a different process, like its parent or cgroup, is left out when processes are compared,
+/// so that history keeps it as the same process.
 pub struct Process {
     pub pid: u32,
-    pub name: String,
-    pub commandline: String,
+    /// Parent process id when the process was first seen, or 0 for processes without a parent.
+    pub ppid: u32,
+    /// Path to the executable, or just its file name where the OS does not tell the full
+    /// path. `None` if it could not be read, e.g. for processes of other users.
+    pub exe: Option<PathBuf>,
+    /// Arguments the process was started with, the first one usually being its own name.
+    pub argv: Vec<OsString>,
+    /// Working directory, read once when the process is first seen.
+    pub cwd: Option<PathBuf>,
+    /// Real user id of the owner.
+    pub uid: Option<u32>,
+    /// Name of the owner, if `uid` is known to the machine memoir runs on.
+    pub user: Option<String>,
+    /// Values of environment variables asked for with `ProcessCache::with_captured_env`, in
+    /// the same order. Variables the process does not have are left out.
+    pub env: Vec<(String, OsString)>,
+    /// Path of the cgroup v2 the process belonged to when first seen, relative to the cgroup
+    /// filesystem root.
+    pub cgroup: Option<String>,
+    /// Milliseconds since the epoch when the process was started. Together with pid, this
+    /// tells apart different processes that happened to get the same pid. The sysinfo backend
+    /// only knows it to a second, so its instance ids do not match ones of the native backend.
+    pub start_time: Option<u64>,
+}
+
+impl Process {
+    /// Executable for display, quoted if it has spaces or bytes that are not UTF-8.
+    pub fn name(&self) -> Cow<'_, str> {
+        match &self.exe {
+            Some(exe) => quote::quote(exe.as_os_str()).into(),
+            None => "?".into(),
+        }
+    }
+
+    /// Arguments quoted the way a shell would need them, and joined with spaces.
+    pub fn commandline(&self) -> String {
+        quote::join(&self.argv)
+    }
+
+    /// Identifier of this particular process, which stays the same during its whole lifetime
+    /// and is not shared with processes that reuse its pid later, e.g. `1234@1705004896927`.
+    pub fn instance_id(&self) -> String {
+        match self.start_time {
+            Some(start) => format!("{}@{start}", self.pid),
+            None => self.pid.to_string(),
+        }
+    }
+
+    /// Memory taken by the process when shared through `Arc`, with all of its strings.
+    pub(crate) fn approx_bytes(&self) -> usize {
+        let path = |p: &Option<PathBuf>| p.as_ref().map_or(0, |p| p.as_os_str().len());
+        // strong and weak counters of `Arc`
+        2 * size_of::<usize>()
+            + size_of::<Self>()
+            + path(&self.exe)
+            + path(&self.cwd)
+            + self.argv.capacity() * size_of::<OsString>()
+            + self.argv.iter().map(|a| a.len()).sum::<usize>()
+            + self.user.as_ref().map_or(0, String::len)
+            + self.env.capacity() * size_of::<(String, OsString)>()
+            + self
+                .env
+                .iter()
+                .map(|(n, v)| n.len() + v.len())
+                .sum::<usize>()
+            + self.cgroup.as_ref().map_or(0, String::len)
+    }
 }
 
+impl PartialEq for Process {
+    fn eq(&self, other: &Self) -> bool {
+        let Process {
+            pid,
+            ppid: _,
+            exe,
+            argv,
+            cwd,
+            uid,
+            user,
+            env,
+            cgroup: _,
+            start_time,
+        } = self;
+        *pid == other.pid
+            && *exe == other.exe
+            && *argv == other.argv
+            && *cwd == other.cwd
+            && *uid == other.uid
+            && *user == other.user
+            && *env == other.env
+            && *start_time == other.start_time
+    }
+}
+
+impl Eq for Process {}
+
+impl Hash for Process {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let Process {
+            pid,
+            ppid: _,
+            exe,
+            argv,
+            cwd,
+            uid,
+            user,
+            env,
+            cgroup: _,
+            start_time,
+        } = self;
+        (pid, exe, argv, cwd, uid, user, env, start_time).hash(state);
+    }
+}
+
+#[derive(Clone, PartialEq)]
 pub struct HistoryEntry {
     pub process: Arc<Process>,
+    /// Resident set size - always available, but counts shared pages in every process.
     pub memory_mb: u64,
+    /// Highest RSS the process ever had, so spikes between two samples are not lost.
+    pub peak_mb: Option<u64>,
+    /// Proportional set size - shared pages divided between processes sharing them.
+    pub pss_mb: Option<u64>,
+    /// Unique set size - private clean and dirty pages only.
+    pub uss_mb: Option<u64>,
+    pub swap_mb: Option<u64>,
+    /// CPU time spent since the previous sample, in percents of a single core (like `top`).
+    /// Not available for the first sample of a process.
+    pub cpu_percent: Option<f32>,
+    /// I/O done since the previous sample. Not available for the first sample of a process,
+    /// and for processes whose I/O statistics are not accessible.
+    pub io: Option<IoRates>,
+    /// State like in `ps`: `R` running, `S` sleeping, `D` in uninterruptible sleep
+    /// (usually waiting for I/O), `Z` zombie, `T` stopped...
+    pub state: Option<char>,
+    /// For a bucket of downsampled history, how memory varied within it. Other values are
+    /// averages over the bucket then. `None` for samples taken as is.
+    pub spread: Option<Spread>,
+}
+
+/// Lowest and highest memory of a process within a bucket of downsampled history.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Spread {
+    /// How many samples the bucket was made of.
+    pub samples: u32,
+    pub memory_mb: (u64, u64),
+    pub pss_mb: Option<(u64, u64)>,
+    pub uss_mb: Option<(u64, u64)>,
+}
+
+/// Per-second I/O rates of a process.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct IoRates {
+    /// Bytes actually fetched from the storage layer.
+    pub read_bytes: u64,
+    /// Bytes actually sent to the storage layer.
+    pub write_bytes: u64,
+    /// Read syscalls, including ones served from page cache.
+    pub syscr: u64,
+    /// Write syscalls.
+    pub syscw: u64,
+}
+
+impl HistoryEntry {
+    /// Memory usage according to the given metric. `None` when the metric could not be
+    /// collected for this process.
+    pub fn memory(&self, metric: MemoryMetric) -> Option<u64> {
+        match metric {
+            MemoryMetric::Rss => Some(self.memory_mb),
+            MemoryMetric::Pss => self.pss_mb,
+            MemoryMetric::Uss => self.uss_mb,
+        }
+    }
+
+    /// Lowest and highest memory according to the given metric, which are both just
+    /// the memory for samples that were not downsampled.
+    pub fn range(&self, metric: MemoryMetric) -> Option<(u64, u64)> {
+        match self.spread {
+            Some(s) => match metric {
+                MemoryMetric::Rss => Some(s.memory_mb),
+                MemoryMetric::Pss => s.pss_mb,
+                MemoryMetric::Uss => s.uss_mb,
+            },
+            None => self.memory(metric).map(|m| (m, m)),
+        }
+    }
+}
+
+/// Which of the collected values should be treated as "memory" of a process in reports.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryMetric {
+    #[default]
+    Rss,
+    Pss,
+    Uss,
+}
+
+impl std::str::FromStr for MemoryMetric {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "rss" => Ok(MemoryMetric::Rss),
+            "pss" => Ok(MemoryMetric::Pss),
+            "uss" => Ok(MemoryMetric::Uss),
+            _ => Err(anyhow!(
+                "Unknown memory metric {s:?}, expected one of: rss, pss, uss"
+            )),
+        }
+    }
 }
 
 impl std::fmt::Display for HistoryEntry {
@@ -19,15 +240,128 @@ impl std::fmt::Display for HistoryEntry {
         write!(
             f,
             "<{} | {} | {} | {}>",
-            self.process.pid, self.process.name, self.memory_mb, self.process.commandline
+            self.process.pid,
+            self.process.name(),
+            self.memory_mb,
+            self.process.commandline()
         )
     }
 }
 
 pub struct CurrentProcesses {
     pub timestamp: u128,
+    /// Milliseconds since the previous iteration, `None` for the first one.
+    pub interval_ms: Option<u64>,
     pub entries: Vec<HistoryEntry>,
+    /// Memory of the system as a whole, to compare the sum of processes against.
+    pub system: Option<SystemMemory>,
+    /// Pressure Stall Information, if the kernel provides it.
+    pub pressure: Option<Pressure>,
+    /// Memory accounting of every cgroup that listed processes belong to.
+    pub cgroups: Vec<CgroupMemory>,
+    /// What happened since the previous iteration.
+    pub events: Vec<Event>,
+}
+
+/// Memory usage and limits of a cgroup v2, from its `memory.*` files. Values are `None` if
+/// the memory controller is not enabled for the cgroup, or the kernel is too old to have them.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct CgroupMemory {
+    pub path: String,
+    /// `memory.current`
+    pub current_mb: Option<u64>,
+    /// `memory.max`; `None` also if there is no limit.
+    pub max_mb: Option<u64>,
+    /// `memory.peak`, since Linux 5.19
+    pub peak_mb: Option<u64>,
+    pub events: Option<MemoryEvents>,
+}
+
+/// Counters from `memory.events` - how many times the cgroup hit its limits.
+#[derive(Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MemoryEvents {
+    /// Reclaimed despite being under `memory.low`.
+    pub low: u64,
+    /// Throttled for being over `memory.high`.
+    pub high: u64,
+    /// Allocation was about to go over `memory.max`.
+    pub max: u64,
+    /// Allocation failed even after reclaim.
+    pub oom: u64,
+    /// Processes killed by the OOM killer.
+    pub oom_kill: u64,
+}
+
+/// System-wide memory usage, named and measured like in `/proc/meminfo` but in MB.
+/// Fields not provided by the OS are `None`.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct SystemMemory {
+    pub total_mb: u64,
+    pub available_mb: Option<u64>,
+    pub free_mb: u64,
+    pub buffers_mb: Option<u64>,
+    /// Page cache, including tmpfs and shared memory.
+    pub cached_mb: Option<u64>,
+    /// tmpfs and shared memory.
+    pub shmem_mb: Option<u64>,
+    /// Kernel data structures.
+    pub slab_mb: Option<u64>,
+    pub swap_total_mb: Option<u64>,
+    pub swap_used_mb: Option<u64>,
+}
+
+impl SystemMemory {
+    /// All values with their `/proc/meminfo`-like names, in a stable order.
+    pub fn fields(&self) -> [(&'static str, Option<u64>); 9] {
+        [
+            ("MemTotal", Some(self.total_mb)),
+            ("MemAvailable", self.available_mb),
+            ("MemFree", Some(self.free_mb)),
+            ("Buffers", self.buffers_mb),
+            ("Cached", self.cached_mb),
+            ("Shmem", self.shmem_mb),
+            ("Slab", self.slab_mb),
+            ("SwapTotal", self.swap_total_mb),
+            ("SwapUsed", self.swap_used_mb),
+        ]
+    }
+}
+/// Pressure Stall Information: how long tasks were waiting for a resource. Resources not
+/// reported by the kernel are `None`.
+#[derive(Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pressure {
+    pub cpu: Option<Stall>,
+    pub memory: Option<Stall>,
+    pub io: Option<Stall>,
+}
+
+impl Pressure {
+    pub fn resources(&self) -> [(&'static str, Option<&Stall>); 3] {
+        [
+            ("cpu", self.cpu.as_ref()),
+            ("memory", self.memory.as_ref()),
+            ("io", self.io.as_ref()),
+        ]
+    }
+}
+
+/// A single PSI line: `some` means at least one task was stalled, `full` - all non-idle
+/// tasks were stalled at once.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct StallRecord {
+    /// Percentage of time tasks were stalled, over the last 10 seconds.
+    pub avg10: f32,
+    /// Total stall time, in microseconds.
+    pub total_us: u64,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Stall {
+    pub some: StallRecord,
+    /// Not reported for CPU by older kernels.
+    pub full: Option<StallRecord>,
 }
+
 impl std::fmt::Display for CurrentProcesses {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         writeln!(f, "--- {} ---", self.timestamp)?;
@@ -38,128 +372,249 @@ impl std::fmt::Display for CurrentProcesses {
     }
 }
 
+/// Cumulative counters of a process as reported by the OS. They only make sense as a difference
+/// between two samples, so they are kept in `ProcessCache` rather than in the history.
+#[derive(Default, Clone, Copy)]
+pub struct Counters {
+    /// Time spent in user and kernel mode.
+    pub cpu_time_ms: Option<u64>,
+    pub io: Option<IoCounters>,
+}
+
+#[derive(Clone, Copy)]
+pub struct IoCounters {
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    pub syscr: u64,
+    pub syscw: u64,
+}
+
+impl Counters {
+    /// Fill in per-interval values of `entry` from the difference with a previous sample.
+    /// Counters going backwards mean something odd happened to the process, so no value is
+    /// better than a wrong one.
+    fn apply_rates(&self, previous: &Counters, elapsed_ms: f32, entry: &mut HistoryEntry) {
+        entry.cpu_percent = match (previous.cpu_time_ms, self.cpu_time_ms) {
+            (Some(p), Some(c)) if c >= p => Some((c - p) as f32 / elapsed_ms * 100.),
+            _ => None,
+        };
+        let per_second = |p: u64, c: u64| ((c - p) as f32 * 1000. / elapsed_ms).round() as u64;
+        entry.io = match (previous.io, self.io) {
+            (Some(p), Some(c))
+                if c.read_bytes >= p.read_bytes
+                    && c.write_bytes >= p.write_bytes
+                    && c.syscr >= p.syscr
+                    && c.syscw >= p.syscw =>
+            {
+                Some(IoRates {
+                    read_bytes: per_second(p.read_bytes, c.read_bytes),
+                    write_bytes: per_second(p.write_bytes, c.write_bytes),
+                    syscr: per_second(p.syscr, c.syscr),
+                    syscw: per_second(p.syscw, c.syscw),
+                })
+            }
+            _ => None,
+        };
+    }
+}
+
+/// State carried between iterations of `list_processes`.
+#[derive(Default)]
+pub struct ProcessCache {
+    processes: HashSet<Arc<Process>>,
+    counters: HashMap<Arc<Process>, Counters>,
+    last_timestamp: Option<u128>,
+    /// Cgroups of processes listed in the previous iteration.
+    cgroups: Vec<String>,
+    oom: OomTracker,
+    filter: Filter,
+    capture_env: Vec<String>,
+}
+
+impl ProcessCache {
+    pub fn with_capacity(capacity: usize) -> Self {
+        ProcessCache {
+            processes: HashSet::with_capacity(capacity),
+            counters: HashMap::with_capacity(capacity),
+            last_timestamp: None,
+            cgroups: Vec::new(),
+            oom: OomTracker::default(),
+            filter: Filter::default(),
+            capture_env: Vec::new(),
+        }
+    }
+
+    /// List only processes accepted by `filter` from now on.
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Record values of these environment variables for every new process from now on.
+    pub fn with_captured_env(mut self, names: Vec<String>) -> Self {
+        self.capture_env = names;
+        self
+    }
+
+    /// Get the same process from cache, or put it there if it was not seen before, so that
+    /// all iterations share a single copy of it.
+    fn intern(&mut self, process: Arc<Process>) -> Arc<Process> {
+        // `Arc<T>` can be compared with `T`, so we can look it up by contents.
+        let cached = match self.processes.get(&*process) {
+            Some(c) => c.clone(),
+            None => process,
+        };
+        let _ins = self.processes.insert(cached.clone());
+        cached
+    }
+
+    /// Replace processes of a sample that was not listed with this cache, like one read back
+    /// from disk, with their cached copies, so that history sees them as the same processes
+    /// as in samples listed later.
+    pub fn adopt(&mut self, sample: &mut CurrentProcesses) {
+        for entry in &mut sample.entries {
+            entry.process = self.intern(entry.process.clone());
+        }
+        for event in &mut sample.events {
+            match event {
+                Event::OomKill {
+                    victim: Some(process),
+                    ..
+                }
+                | Event::Started { process, .. }
+                | Event::Exited { process, .. } => *process = self.intern(process.clone()),
+                Event::OomKill { victim: None, .. } => {}
+            }
+        }
+    }
+
+    /// Memory taken by the cache, roughly. Processes themselves are not counted, as they are
+    /// shared with the history.
+    pub fn approx_bytes(&self) -> usize {
+        size_of::<Self>()
+            + map_bytes::<Arc<Process>, ()>(self.processes.len())
+            + map_bytes::<Arc<Process>, Counters>(self.counters.len())
+    }
+
+    /// Forget processes that are not referenced from anywhere else anymore.
+    pub fn cleanup(&mut self) {
+        self.processes.retain(|c| Arc::strong_count(c) > 1);
+    }
+}
+
 // List all processes that are currently running. Since most of pids and names will be repeated
 // between iterations, use a cache to avoid having tens of megabytes of same strings in memory.
-pub fn list_processes(process_cache: &mut HashSet<Arc<Process>>) -> Result<CurrentProcesses> {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .context("Time went backwards! TODO: support timey-wimey stuff in memoir")?
-        .as_millis();
+pub fn list_processes<S: ProcessSource + ?Sized>(
+    source: &mut S,
+    process_cache: &mut ProcessCache,
+) -> Result<CurrentProcesses> {
+    let now = source.refresh()?;
+    let listed = source.processes(&process_cache.filter, &process_cache.capture_env)?;
+    let current_cgroups: HashSet<&str> = listed
+        .iter()
+        .filter_map(|(e, _)| e.process.cgroup.as_deref())
+        .collect();
+    // a cgroup whose last process was just killed for running out of memory has nothing
+    // listed in it anymore, but the kill is only seen in its counters
+    let mut cgroups: Vec<&str> = current_cgroups
+        .iter()
+        .copied()
+        .chain(process_cache.cgroups.iter().map(String::as_str))
+        .collect();
+    cgroups.sort();
+    cgroups.dedup();
+    let cgroups = source.cgroup_memory(&cgroups);
+    process_cache.cgroups = current_cgroups.into_iter().map(String::from).collect();
+
+    let elapsed_ms = process_cache
+        .last_timestamp
+        .filter(|&last| now > last)
+        .map(|last| (now - last) as f32);
+    let mut counters = HashMap::with_capacity(listed.len());
+    let mut entries = Vec::with_capacity(listed.len());
+    for (mut entry, current) in listed {
+        entry.process = process_cache.intern(entry.process);
+        if let (Some(elapsed_ms), Some(previous)) =
+            (elapsed_ms, process_cache.counters.get(&entry.process))
+        {
+            current.apply_rates(previous, elapsed_ms, &mut entry);
+        }
+        counters.insert(entry.process.clone(), current);
+        entries.push(entry);
+    }
+    process_cache.counters = counters;
+    process_cache.last_timestamp = Some(now);
+    let events = process_cache
+        .oom
+        .update(&entries, &cgroups, source.oom_kills());
+
     Ok(CurrentProcesses {
         timestamp: now,
-        entries: platform_specific::platform_list_processes(process_cache),
+        interval_ms: elapsed_ms.map(|e| e as u64),
+        entries,
+        system: source.system_memory(),
+        pressure: source.pressure(),
+        cgroups,
+        events,
     })
 }
 
-#[cfg(target_os = "linux")]
-mod platform_specific {
+#[cfg(test)]
+mod tests {
     use super::*;
-    use std::{collections::HashSet, sync::Arc};
-
-    pub fn platform_list_processes(process_cache: &mut HashSet<Arc<Process>>) -> Vec<HistoryEntry> {
-        let page_size: u64 = procfs::page_size();
-        let mut entries: Vec<HistoryEntry> = Vec::with_capacity(100);
-        // panic if cannot list processes at all - this is unexpected
-        for prc in procfs::process::all_processes().unwrap() {
-            // but silently ignore everything we cannot access - processes may die
-            if prc.is_err() {
-                continue;
-            }
-            let prc = prc.unwrap();
-            let stat = prc.stat();
-            if stat.is_err() {
-                continue;
-            }
-            let stat = stat.unwrap();
-            if stat.rss == 0 {
-                continue;
-            }
-            let executable = match prc.exe() {
-                Ok(e) => match e.into_os_string().into_string() {
-                    Ok(e) => e,
-                    Err(_) => String::from("?"),
-                },
-                Err(_) => String::from("?"),
-            };
-            let cmd = match prc.cmdline() {
-                Ok(c) => c.join(" "),
-                Err(_) => String::from("?"),
-            };
-            // `Arc<T>` can be compared with `T`, so we can get ref-counted process from
-            // cache by its "raw" structure.
-            let potential_entry = Process {
-                pid: prc.pid as u32,
-                name: executable,
-                commandline: cmd,
-            };
-            let cached = match process_cache.get(&potential_entry) {
-                Some(c) => c.clone(),
-                None => Arc::from(potential_entry),
-            };
-            let _ins = process_cache.insert(cached.clone());
-
-            entries.push(HistoryEntry {
-                process: cached,
-                memory_mb: stat.rss * page_size / 1_000_000,
-            })
-        }
-        entries
+    use crate::source::{ScriptedSample, ScriptedSource};
+
+    #[test]
+    fn reparented() {
+        let mut source = ScriptedSource::new([
+            ScriptedSample::new(1_000)
+                .with_process(1, 0, "init", 10)
+                .with_process(100, 50, "daemon", 20),
+            // the parent exited, and the process got adopted by init
+            ScriptedSample::new(2_000)
+                .with_process(1, 0, "init", 10)
+                .with_process(100, 1, "daemon", 20),
+        ]);
+        let mut cache = ProcessCache::default();
+        let first = list_processes(&mut source, &mut cache).unwrap();
+        let second = list_processes(&mut source, &mut cache).unwrap();
+        assert!(Arc::ptr_eq(
+            &first.entries[1].process,
+            &second.entries[1].process
+        ));
     }
-}
 
-#[cfg(target_os = "windows")]
-mod platform_specific {
-    #![allow(non_camel_case_types)]
-    #![allow(non_snake_case)]
-    use super::*;
-    use serde::Deserialize;
-
-    // needs to be named exactly like the entity in WMI
-    #[derive(Deserialize, Debug)]
-    struct Win32_Process {
-        ProcessId: u32,
-        Name: Option<String>,
-        WorkingSetSize: u64,
-        CommandLine: Option<String>,
-    }
-
-    pub fn platform_list_processes(process_cache: &mut HashSet<Arc<Process>>) -> Vec<HistoryEntry> {
-        let mut entries: Vec<HistoryEntry> = Vec::with_capacity(100);
-        let com_con = wmi::COMLibrary::new().expect(
-            "Could not acquire COM library to query WMI.\n\
-            Either you are missing some privilegies, or something is broken in your system.",
-        );
-        let wmi_con = wmi::WMIConnection::new(com_con).expect(
-            "Could not establish WMI connection.\n\
-            Either you are missing some privilegies, or something is broken in your system.",
-        );
-        // TODO: Win32_Process.WorkingSetSize is not exactly what we need... Better join with
-        // Win32_PerfRawData_PerfProc_Process on WP.ProcessId == WPRDPPP.IDProcess, and get
-        // WorkingSetPrivate from there.
-        let result: Vec<Win32_Process> = wmi_con.query().expect(
-            "Could not query WMI for processes.\n\
-                Either you are missing some privilegies, or something is broken in your system.",
-        );
-        for r in result {
-            // `Arc<T>` can be compared with `T`, so we can get ref-counted process from
-            // cache by its "raw" structure.
-            let potential_entry = Process {
-                pid: r.ProcessId,
-                name: r.Name.unwrap_or("?".to_string()),
-                commandline: r.CommandLine.unwrap_or("?".to_string()),
-            };
-            let cached = match process_cache.get(&potential_entry) {
-                Some(c) => c.clone(),
-                None => Arc::from(potential_entry),
-            };
-            let _ins = process_cache.insert(cached.clone());
-
-            entries.push(HistoryEntry {
-                process: cached,
-                memory_mb: r.WorkingSetSize / 1_000_000,
-            });
-        }
-        entries
+    #[test]
+    fn oom_in_single_process_container() {
+        let cgroup = |oom_kill| CgroupMemory {
+            path: "/docker/abc".to_string(),
+            current_mb: None,
+            max_mb: None,
+            peak_mb: None,
+            events: Some(MemoryEvents {
+                oom_kill,
+                ..Default::default()
+            }),
+        };
+        let mut running = ScriptedSample::new(1_000)
+            .with_process(1, 0, "init", 10)
+            .with_process(100, 1, "app", 500)
+            .in_cgroup("/docker/abc");
+        running.cgroups.push(cgroup(0));
+        // the only process of the container got killed, leaving it empty
+        let mut killed = ScriptedSample::new(2_000).with_process(1, 0, "init", 10);
+        killed.cgroups.push(cgroup(1));
+        let mut source = ScriptedSource::new([running, killed]);
+        let mut cache = ProcessCache::default();
+        list_processes(&mut source, &mut cache).unwrap();
+        let second = list_processes(&mut source, &mut cache).unwrap();
+        let kill = second
+            .events
+            .iter()
+            .find(|e| matches!(e, Event::OomKill { .. }))
+            .unwrap();
+        assert_eq!(kill.cgroup(), Some("/docker/abc"));
+        assert_eq!(kill.process().map(|p| p.pid), Some(100));
+        assert_eq!(kill.memory_mb(), Some(500));
     }
 }
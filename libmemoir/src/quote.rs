@@ -0,0 +1,151 @@
+use std::ffi::OsStr;
+#[cfg(any(target_os = "windows", test))]
+use std::ffi::OsString;
+
+/// Quote a single argument so that a POSIX shell would read it back unchanged: as is when
+/// it only has safe characters, in single quotes when it has spaces or shell syntax, and
+/// in `$'...'` with escapes when it has control characters or is not valid UTF-8.
+pub fn quote(arg: &OsStr) -> String {
+    let bytes = arg.as_encoded_bytes();
+    if bytes.is_empty() {
+        return String::from("''");
+    }
+    let safe = |b: &u8| b.is_ascii_alphanumeric() || b"-_./:=@%+,^".contains(b);
+    if bytes.iter().all(safe) {
+        return String::from_utf8_lossy(bytes).into_owned();
+    }
+    if let Ok(s) = std::str::from_utf8(bytes) {
+        if !s.chars().any(char::is_control) {
+            return format!("'{}'", s.replace('\'', r"'\''"));
+        }
+    }
+    let mut quoted = String::from("$'");
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => quoted.push_str(r"\\"),
+                '\'' => quoted.push_str(r"\'"),
+                '\n' => quoted.push_str(r"\n"),
+                '\t' => quoted.push_str(r"\t"),
+                '\r' => quoted.push_str(r"\r"),
+                c if c.is_ascii_control() => quoted.push_str(&format!(r"\x{:02x}", c as u8)),
+                c if c.is_control() => quoted.push_str(&format!(r"\u{:04x}", c as u32)),
+                c => quoted.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            quoted.push_str(&format!(r"\x{b:02x}"));
+        }
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// Quote every argument and join them with spaces, to be pasted back into a shell.
+pub fn join<S: AsRef<OsStr>>(args: &[S]) -> String {
+    args.iter()
+        .map(|a| quote(a.as_ref()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Split a Windows command line into arguments the same way the C runtime of the process
+/// did it, since Windows only keeps the command line as a whole.
+#[cfg(any(target_os = "windows", test))]
+pub fn split_windows(commandline: &str) -> Vec<OsString> {
+    let mut args = Vec::new();
+    let mut chars = commandline.chars().peekable();
+    // the program name is taken literally up to the first space outside of quotes
+    let mut program = String::new();
+    let mut in_quotes = false;
+    for c in chars.by_ref() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ' ' | '\t' if !in_quotes => break,
+            c => program.push(c),
+        }
+    }
+    args.push(OsString::from(program));
+
+    loop {
+        while chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+        let mut arg = String::new();
+        let mut in_quotes = false;
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    let mut backslashes = 1;
+                    while chars.next_if_eq(&'\\').is_some() {
+                        backslashes += 1;
+                    }
+                    // backslashes are only special right before a quote
+                    if chars.peek() == Some(&'"') {
+                        arg.extend(std::iter::repeat_n('\\', backslashes / 2));
+                        if backslashes % 2 == 1 {
+                            arg.push(chars.next().unwrap());
+                        }
+                    } else {
+                        arg.extend(std::iter::repeat_n('\\', backslashes));
+                    }
+                }
+                // a doubled quote inside quotes is a literal one
+                '"' if in_quotes && chars.peek() == Some(&'"') => {
+                    arg.push(chars.next().unwrap());
+                }
+                '"' => in_quotes = !in_quotes,
+                ' ' | '\t' if !in_quotes => break,
+                c => arg.push(c),
+            }
+        }
+        args.push(OsString::from(arg));
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoting() {
+        let q = |s: &str| quote(OsStr::new(s));
+        assert_eq!(q("ninja"), "ninja");
+        assert_eq!(
+            q("-DCMAKE_BUILD_TYPE=Release"),
+            "-DCMAKE_BUILD_TYPE=Release"
+        );
+        assert_eq!(q(""), "''");
+        assert_eq!(q("build dir"), "'build dir'");
+        assert_eq!(q("it's"), r"'it'\''s'");
+        assert_eq!(q("a\tb\nc'"), r"$'a\tb\nc\''");
+        assert_eq!(join(&["echo", "a b", "c"]), "echo 'a b' c");
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            assert_eq!(quote(OsStr::from_bytes(b"caf\xe9")), r"$'caf\xe9'");
+        }
+    }
+
+    #[test]
+    fn windows_commandline() {
+        let split = |s: &str| -> Vec<String> {
+            split_windows(s)
+                .into_iter()
+                .map(|a| a.into_string().unwrap())
+                .collect()
+        };
+        assert_eq!(
+            split(r#""C:\Program Files\cl.exe" /c "a b.cpp" x"#),
+            [r"C:\Program Files\cl.exe", "/c", "a b.cpp", "x"]
+        );
+        assert_eq!(
+            split(r#"a.exe \\server\share "q\"uote" "tail\\" """#),
+            ["a.exe", r"\\server\share", r#"q"uote"#, r"tail\", ""]
+        );
+        assert_eq!(split(r#"a.exe "say ""hi""""#), ["a.exe", r#"say "hi""#]);
+        assert_eq!(split("a.exe"), ["a.exe"]);
+    }
+}
@@ -0,0 +1,24 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+use crate::csvdump::{self, ExportOptions};
+use crate::history::History;
+use crate::{jsondump, native};
+
+/// Write samples taken within the last `time_sec` seconds to `destination`, in the format
+/// asked for by its extension: `.memoir` for the native format, which keeps everything,
+/// `.json` for JSON, and tab-separated CSV for anything else.
+pub(crate) fn save_to_file(
+    history: &History,
+    destination: &PathBuf,
+    time_sec: Option<usize>,
+    options: &ExportOptions,
+) -> Result<()> {
+    let extension = destination.extension().map(|e| e.to_ascii_lowercase());
+    match extension.as_ref().and_then(|e| e.to_str()) {
+        Some("memoir") => native::save_to_file(history, destination, time_sec),
+        Some("json") => jsondump::save_to_file(history, destination, time_sec, options),
+        _ => csvdump::save_to_file(history, destination, time_sec, options),
+    }
+}
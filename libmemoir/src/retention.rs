@@ -0,0 +1,558 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::mem::size_of;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+use crate::daemon::Tier;
+use crate::events::Event;
+use crate::history::{map_bytes, History};
+use crate::process::{
+    CgroupMemory, CurrentProcesses, HistoryEntry, IoRates, Pressure, Process, Spread, SystemMemory,
+};
+
+/// History kept by the daemon: samples as they were taken for `keep`, and then buckets of
+/// every tier, each coarser and kept for longer than the previous one.
+///
+/// Samples are merged into buckets of the first tier when they get older than `keep`, and
+/// buckets are merged into the next tier when they get older than their own tier keeps them.
+pub struct Retention {
+    keep: Duration,
+    samples: History,
+    levels: Vec<Level>,
+    max_bytes: Option<usize>,
+    /// Memory taken by the process cache of the daemon, which counts against `max_bytes` too.
+    cache_bytes: usize,
+}
+
+struct Level {
+    tier: Tier,
+    /// Bucket still being filled, with samples younger than anything in `history`.
+    bucket: Option<Bucket>,
+    history: History,
+}
+
+impl Retention {
+    pub fn new(keep: Duration, tiers: &[Tier]) -> Result<Self> {
+        let mut previous = Tier {
+            resolution: Duration::ZERO,
+            keep,
+        };
+        for tier in tiers {
+            if tier.resolution <= previous.resolution || tier.keep <= previous.keep {
+                return Err(anyhow!(
+                    "Downsampling tier {:?}:{:?} must have longer buckets, and keep them for \
+                    longer than the previous tier or raw samples",
+                    tier.resolution,
+                    tier.keep
+                ));
+            }
+            previous = tier.clone();
+        }
+        Ok(Retention {
+            keep,
+            samples: History::new(),
+            levels: tiers
+                .iter()
+                .map(|tier| Level {
+                    tier: tier.clone(),
+                    bucket: None,
+                    history: History::new(),
+                })
+                .collect(),
+            max_bytes: None,
+            cache_bytes: 0,
+        })
+    }
+
+    /// Keep memory taken by history and the process cache under `max_bytes`, by merging
+    /// samples into buckets before their time, and dropping the oldest data when there is
+    /// nothing left to merge.
+    pub fn with_max_bytes(mut self, max_bytes: Option<usize>) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Samples taken within `keep`, as they were taken.
+    pub fn samples(&self) -> &History {
+        &self.samples
+    }
+
+    /// Add a new sample, with `cache_bytes` currently taken by the process cache.
+    pub fn push(&mut self, sample: CurrentProcesses, cache_bytes: usize) {
+        let now = sample.timestamp;
+        self.cache_bytes = cache_bytes;
+        self.samples.push(sample);
+        let mut aged = self
+            .samples
+            .trim_before(now.saturating_sub(self.keep.as_millis()));
+        for level in &mut self.levels {
+            for sample in aged {
+                level.add(sample);
+            }
+            aged = level
+                .history
+                .trim_before(now.saturating_sub(level.tier.keep.as_millis()));
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            while self.approx_bytes() + self.cache_bytes > max_bytes && self.evict() {}
+        }
+    }
+
+    /// Free some memory, the finest data first. Returns false if there is nothing to free.
+    fn evict(&mut self) -> bool {
+        // the newest sample is kept, new ones are compared against it
+        if self.samples.len() > 1 {
+            let sample = self.samples.pop_front().unwrap();
+            if let Some(level) = self.levels.first_mut() {
+                level.add(sample);
+            }
+            return true;
+        }
+        for i in 0..self.levels.len() {
+            if let Some(bucket) = self.levels[i].history.pop_front() {
+                if let Some(next) = self.levels.get_mut(i + 1) {
+                    next.add(bucket);
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Memory taken by the history, roughly.
+    pub fn approx_bytes(&self) -> usize {
+        let histories =
+            || std::iter::once(&self.samples).chain(self.levels.iter().map(|l| &l.history));
+        let buckets = || self.levels.iter().filter_map(|l| l.bucket.as_ref());
+        // the same process is usually in samples and buckets of every tier at once
+        let mut seen = HashSet::new();
+        let processes: usize = histories()
+            .flat_map(History::processes)
+            .chain(buckets().flat_map(|b| b.processes.iter().map(|(p, _)| p)))
+            .filter(|p| seen.insert(Arc::as_ptr(p)))
+            .map(|p| p.approx_bytes())
+            .sum();
+        size_of::<Self>()
+            + histories().map(History::frames_bytes).sum::<usize>()
+            + buckets().map(Bucket::approx_bytes).sum::<usize>()
+            + processes
+    }
+
+    /// What is kept and how much memory it takes, to be shown to the user.
+    pub fn status(&self) -> String {
+        let span = |h: &History| match (h.front_timestamp(), h.back_timestamp()) {
+            (Some(front), Some(back)) => (back - front) / 1000,
+            _ => 0,
+        };
+        let mut status = format!(
+            "Samples: {} over {} s\n",
+            self.samples.len(),
+            span(&self.samples)
+        );
+        for level in &self.levels {
+            status += &format!(
+                "{:?} buckets: {}\n",
+                level.tier.resolution,
+                level.history.len() + usize::from(level.bucket.is_some()),
+            );
+        }
+        let history = self.approx_bytes();
+        status += &format!(
+            "Memory: {}, of which history {} and process cache {}",
+            human_size(history + self.cache_bytes),
+            human_size(history),
+            human_size(self.cache_bytes),
+        );
+        if let Some(max_bytes) = self.max_bytes {
+            status += &format!(", limited to {}", human_size(max_bytes));
+        }
+        status
+    }
+
+    /// Everything taken since `since`, oldest first: buckets of the coarsest tier, then of
+    /// finer ones, then samples as they were taken. Buckets overlapping `since` are included.
+    pub fn window(&self, since: u128) -> Cow<'_, History> {
+        if self
+            .levels
+            .iter()
+            .all(|l| l.bucket.is_none() && l.history.is_empty())
+        {
+            return Cow::Borrowed(&self.samples);
+        }
+        let mut window = History::new();
+        for level in self.levels.iter().rev() {
+            let bucket = level.bucket.as_ref().map(Bucket::merged);
+            for sample in level.history.iter().chain(bucket) {
+                // a bucket started before `since` still has samples taken after it
+                let end = sample.timestamp + u128::from(sample.interval_ms.unwrap_or_default());
+                if end > since {
+                    window.push(sample);
+                }
+            }
+        }
+        for sample in self.samples.iter().filter(|s| s.timestamp >= since) {
+            window.push(sample);
+        }
+        Cow::Owned(window)
+    }
+}
+
+fn human_size(bytes: usize) -> String {
+    let mut size = bytes as f64;
+    for unit in ["B", "KB", "MB"] {
+        if size < 1000. {
+            return format!("{size:.1} {unit}");
+        }
+        size /= 1000.;
+    }
+    format!("{size:.1} GB")
+}
+
+impl Level {
+    fn add(&mut self, sample: CurrentProcesses) {
+        let resolution = self.tier.resolution.as_millis();
+        let start = sample.timestamp - sample.timestamp % resolution;
+        if let Some(bucket) = self.bucket.take_if(|b| b.start != start) {
+            self.history.push(bucket.merged());
+        }
+        self.bucket
+            .get_or_insert_with(|| Bucket::new(start, resolution as u64))
+            .add(sample);
+    }
+}
+
+/// Samples within a time span, merged into one.
+struct Bucket {
+    start: u128,
+    length_ms: u64,
+    /// End of the latest sample added, so a bucket still being filled does not claim
+    /// the time after it.
+    end: u128,
+    processes: Vec<(Arc<Process>, Merged)>,
+    /// Position in `processes` by address of the process, which history keeps the same
+    /// while the process is running.
+    index: HashMap<usize, usize>,
+    system: Option<SystemMemory>,
+    pressure: Option<Pressure>,
+    cgroups: Vec<CgroupMemory>,
+    events: Vec<Event>,
+}
+
+impl Bucket {
+    fn new(start: u128, length_ms: u64) -> Self {
+        Bucket {
+            start,
+            length_ms,
+            end: start,
+            processes: Vec::new(),
+            index: HashMap::new(),
+            system: None,
+            pressure: None,
+            cgroups: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, sample: CurrentProcesses) {
+        let end = sample.timestamp + u128::from(sample.interval_ms.unwrap_or_default());
+        self.end = end.clamp(self.end, self.start + u128::from(self.length_ms));
+        for entry in &sample.entries {
+            let address = Arc::as_ptr(&entry.process) as usize;
+            let position = *self.index.entry(address).or_insert_with(|| {
+                self.processes
+                    .push((entry.process.clone(), Merged::default()));
+                self.processes.len() - 1
+            });
+            self.processes[position].1.add(entry);
+        }
+        // system-wide values are the latest ones, like for a sample taken at the end
+        self.system = sample.system.or(self.system.take());
+        self.pressure = sample.pressure.or(self.pressure.take());
+        self.cgroups = sample.cgroups;
+        self.events.extend(sample.events);
+    }
+
+    /// Memory taken by the bucket without the processes, which histories may share.
+    fn approx_bytes(&self) -> usize {
+        size_of::<Self>()
+            + self.processes.capacity() * (size_of::<Arc<Process>>() + size_of::<Merged>())
+            + map_bytes::<usize, usize>(self.index.len())
+            + self.cgroups.capacity() * size_of::<CgroupMemory>()
+            + self.cgroups.iter().map(|c| c.path.len()).sum::<usize>()
+            + self.events.capacity() * size_of::<Event>()
+    }
+
+    fn merged(&self) -> CurrentProcesses {
+        CurrentProcesses {
+            timestamp: self.start,
+            interval_ms: Some((self.end - self.start) as u64),
+            entries: self
+                .processes
+                .iter()
+                .map(|(process, merged)| merged.entry(process.clone()))
+                .collect(),
+            system: self.system.clone(),
+            pressure: self.pressure.clone(),
+            cgroups: self.cgroups.clone(),
+            events: self.events.clone(),
+        }
+    }
+}
+
+/// Values of a process over all samples of a bucket it was in.
+#[derive(Default)]
+struct Merged {
+    samples: u32,
+    memory: Memory,
+    pss: Memory,
+    uss: Memory,
+    peak_mb: Option<u64>,
+    swap: Mean,
+    cpu: Mean,
+    io: [Mean; 4],
+    state: Option<char>,
+}
+
+impl Merged {
+    fn add(&mut self, entry: &HistoryEntry) {
+        // buckets of a finer tier weigh as much as samples they were made of
+        let spread = entry.spread;
+        let weight = spread.map_or(1, |s| s.samples);
+        self.samples += weight;
+        self.memory
+            .add(Some(entry.memory_mb), spread.map(|s| s.memory_mb), weight);
+        self.pss
+            .add(entry.pss_mb, spread.and_then(|s| s.pss_mb), weight);
+        self.uss
+            .add(entry.uss_mb, spread.and_then(|s| s.uss_mb), weight);
+        self.peak_mb = self.peak_mb.max(entry.peak_mb);
+        self.swap.add(entry.swap_mb.map(|v| v as f64), weight);
+        self.cpu.add(entry.cpu_percent.map(f64::from), weight);
+        if let Some(io) = entry.io {
+            let values = [io.read_bytes, io.write_bytes, io.syscr, io.syscw];
+            for (mean, value) in self.io.iter_mut().zip(values) {
+                mean.add(Some(value as f64), weight);
+            }
+        }
+        self.state = entry.state.or(self.state);
+    }
+
+    fn entry(&self, process: Arc<Process>) -> HistoryEntry {
+        let [read_bytes, write_bytes, syscr, syscw] = &self.io;
+        HistoryEntry {
+            process,
+            memory_mb: self.memory.mean.rounded().unwrap_or_default(),
+            peak_mb: self.peak_mb,
+            pss_mb: self.pss.mean.rounded(),
+            uss_mb: self.uss.mean.rounded(),
+            swap_mb: self.swap.rounded(),
+            cpu_percent: self.cpu.get().map(|c| c as f32),
+            io: read_bytes.rounded().map(|read_bytes| IoRates {
+                read_bytes,
+                write_bytes: write_bytes.rounded().unwrap_or_default(),
+                syscr: syscr.rounded().unwrap_or_default(),
+                syscw: syscw.rounded().unwrap_or_default(),
+            }),
+            state: self.state,
+            spread: Some(Spread {
+                samples: self.samples,
+                memory_mb: self.memory.range.unwrap_or_default(),
+                pss_mb: self.pss.range,
+                uss_mb: self.uss.range,
+            }),
+        }
+    }
+}
+
+/// Average of a value over samples where it was known.
+#[derive(Default)]
+struct Mean {
+    sum: f64,
+    weight: u32,
+}
+
+impl Mean {
+    fn add(&mut self, value: Option<f64>, weight: u32) {
+        if let Some(value) = value {
+            self.sum += value * weight as f64;
+            self.weight += weight;
+        }
+    }
+
+    fn get(&self) -> Option<f64> {
+        (self.weight > 0).then(|| self.sum / self.weight as f64)
+    }
+
+    fn rounded(&self) -> Option<u64> {
+        self.get().map(|v| v.round() as u64)
+    }
+}
+
+/// Average, lowest and highest memory.
+#[derive(Default)]
+struct Memory {
+    mean: Mean,
+    range: Option<(u64, u64)>,
+}
+
+impl Memory {
+    fn add(&mut self, value: Option<u64>, range: Option<(u64, u64)>, weight: u32) {
+        let Some(value) = value else {
+            return;
+        };
+        self.mean.add(Some(value as f64), weight);
+        let (low, high) = range.unwrap_or((value, value));
+        self.range = Some(match self.range {
+            Some((l, h)) => (l.min(low), h.max(high)),
+            None => (low, high),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::ScriptedSample;
+
+    /// `init` using 10 MB more every second, and a process living for 5 seconds.
+    fn sample(second: u64, processes: &ScriptedSample) -> CurrentProcesses {
+        let init = (1, second * 10);
+        let memory: &[(u32, u64)] = if second < 5 {
+            &[init, (2, 100)]
+        } else {
+            &[init]
+        };
+        let mut sample = processes.at(second as u128 * 1000, memory).into_sample();
+        sample.interval_ms = Some(1000);
+        sample
+    }
+
+    fn processes() -> ScriptedSample {
+        ScriptedSample::new(0)
+            .with_process(1, 0, "init", 0)
+            .with_process(2, 1, "short", 100)
+    }
+
+    fn tier(resolution: u64, keep: u64) -> Tier {
+        Tier {
+            resolution: Duration::from_secs(resolution),
+            keep: Duration::from_secs(keep),
+        }
+    }
+
+    #[test]
+    fn downsampling() {
+        let processes = processes();
+        let secs = Duration::from_secs;
+        assert!(Retention::new(secs(3600), &[tier(10, 1800)]).is_err());
+        assert!(Retention::new(secs(2), &[tier(10, 20), tier(10, 3600)]).is_err());
+
+        let mut retention = Retention::new(secs(2), &[tier(10, 20), tier(20, 3600)]).unwrap();
+        for second in 0..=45 {
+            retention.push(sample(second, &processes), 0);
+        }
+        let window: Vec<CurrentProcesses> = retention.window(0).iter().collect();
+        let timestamps: Vec<(u128, Option<u64>)> = window
+            .iter()
+            .map(|s| (s.timestamp, s.interval_ms))
+            .collect();
+        // 20 s buckets, 10 s buckets, then samples; buckets still being filled only cover
+        // the samples added so far
+        assert_eq!(
+            timestamps,
+            [
+                (0, Some(20_000)),
+                (20_000, Some(10_000)),
+                (30_000, Some(10_000)),
+                (40_000, Some(3000)),
+                (43_000, Some(1000)),
+                (44_000, Some(1000)),
+                (45_000, Some(1000)),
+            ]
+        );
+        for pair in timestamps.windows(2) {
+            let ((start, interval), (next, _)) = (pair[0], pair[1]);
+            assert!(start + u128::from(interval.unwrap()) <= next);
+        }
+        let values = |entry: &HistoryEntry| {
+            let spread = entry.spread.unwrap();
+            (entry.memory_mb, spread.memory_mb, spread.samples)
+        };
+        assert_eq!(values(&window[0].entries[0]), (95, (0, 190), 20));
+        assert_eq!(values(&window[0].entries[1]), (100, (100, 100), 5));
+        assert_eq!(values(&window[3].entries[0]), (410, (400, 420), 3));
+        assert!(window[4].entries[0].spread.is_none());
+        assert_eq!(
+            window[4].entries[0].range(Default::default()),
+            Some((430, 430))
+        );
+
+        let recent: Vec<u128> = retention
+            .window(40_000)
+            .iter()
+            .map(|s| s.timestamp)
+            .collect();
+        assert_eq!(recent, [40_000, 43_000, 44_000, 45_000]);
+        let recent: Vec<u128> = retention
+            .window(35_000)
+            .iter()
+            .map(|s| s.timestamp)
+            .collect();
+        // the 10 s bucket started before, but ends after
+        assert_eq!(recent, [30_000, 40_000, 43_000, 44_000, 45_000]);
+    }
+
+    #[test]
+    fn shared_processes() {
+        let commandline = format!("sleep {}", "x".repeat(100_000));
+        let processes = ScriptedSample::new(0).with_process(1, 0, &commandline, 10);
+        let mut retention = Retention::new(Duration::from_secs(2), &[tier(10, 3600)]).unwrap();
+        for second in 0..=25 {
+            let mut sample = processes.at(second * 1000, &[(1, 10)]).into_sample();
+            sample.interval_ms = Some(1000);
+            retention.push(sample, 0);
+        }
+        // the process is in samples, in finished buckets and in the one being filled
+        assert!(retention.samples().approx_bytes() > 100_000);
+        assert!(retention.approx_bytes() < 150_000);
+    }
+
+    #[test]
+    fn memory_limit() {
+        let processes = processes();
+        let hour = Duration::from_secs(3600);
+        let mut unlimited = Retention::new(hour, &[]).unwrap();
+        for second in 0..100 {
+            unlimited.push(sample(second, &processes), 0);
+        }
+        let max_bytes = unlimited.approx_bytes() / 2;
+
+        // without tiers, the oldest samples are dropped
+        let mut dropping = Retention::new(hour, &[])
+            .unwrap()
+            .with_max_bytes(Some(max_bytes));
+        for second in 0..100 {
+            dropping.push(sample(second, &processes), 0);
+        }
+        assert!(dropping.approx_bytes() <= max_bytes);
+        assert!(dropping.samples().front_timestamp() > Some(0));
+        assert_eq!(dropping.samples().back_timestamp(), Some(99_000));
+
+        // with them, samples are merged into buckets before their time
+        let mut merging = Retention::new(hour, &[tier(10, 7200)])
+            .unwrap()
+            .with_max_bytes(Some(max_bytes));
+        for second in 0..100 {
+            merging.push(sample(second, &processes), 0);
+        }
+        assert!(merging.approx_bytes() <= max_bytes);
+        assert_eq!(merging.window(0).front_timestamp(), Some(0));
+        assert_eq!(merging.samples().back_timestamp(), Some(99_000));
+        // the process cache counts too
+        merging.push(sample(100, &processes), max_bytes);
+        assert_eq!(merging.samples().len(), 1);
+    }
+}
@@ -0,0 +1,434 @@
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::process::{CurrentProcesses, Process};
+use crate::stored::{os_bytes, os_string, Decoder, Encoder, StoredSample};
+
+/// Written at the start of every segment, with the version of the format.
+const MAGIC: &[u8] = b"memoir segment 1\n";
+const EXTENSION: &str = "segment";
+
+/// Samples appended to files in a directory as they are taken, so history survives a restart
+/// of the daemon.
+///
+/// The log is split into segments, each covering a part of `keep` and starting with a keyframe,
+/// so old segments can be deleted as a whole. Every record is checksummed and synced to disk,
+/// and a record cut short by a crash ends its segment without losing anything before it.
+pub struct SegmentLog {
+    dir: PathBuf,
+    /// How long samples are needed for; segments with only older samples are deleted.
+    keep: Duration,
+    /// How much time a segment covers before the next one is started.
+    span: Duration,
+    current: Option<Segment>,
+}
+
+/// The segment being written, with what its samples are compared against.
+struct Segment {
+    file: File,
+    first_timestamp: u128,
+    encoder: Encoder,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Record {
+    /// A process referenced by `id` from samples after it, until the end of the segment.
+    Process {
+        id: u32,
+        process: StoredProcess,
+    },
+    Sample(Box<StoredSample>),
+}
+
+/// `Process` with OS strings as bytes.
+#[derive(Serialize, Deserialize)]
+struct StoredProcess {
+    pid: u32,
+    ppid: u32,
+    exe: Option<ByteBuf>,
+    argv: Vec<ByteBuf>,
+    cwd: Option<ByteBuf>,
+    uid: Option<u32>,
+    user: Option<String>,
+    env: Vec<(String, ByteBuf)>,
+    cgroup: Option<String>,
+    start_time: Option<u64>,
+}
+
+impl SegmentLog {
+    /// Log to `dir`, creating it if needed, and keep samples there for `keep`.
+    /// Nothing is written until the first sample, which starts a new segment.
+    pub fn new(dir: &Path, keep: Duration) -> Result<Self> {
+        fs::create_dir_all(dir).context(format!("Could not create data directory {:?}", dir))?;
+        Ok(SegmentLog {
+            dir: dir.to_path_buf(),
+            keep,
+            span: (keep / 8).clamp(Duration::from_secs(60), Duration::from_secs(3600)),
+            current: None,
+        })
+    }
+
+    /// Append a sample to the current segment, starting a new one when the current one
+    /// has covered its span.
+    pub fn append(&mut self, sample: &CurrentProcesses) -> Result<()> {
+        let rotate = self.current.as_ref().is_none_or(|s| {
+            sample.timestamp.saturating_sub(s.first_timestamp) >= self.span.as_millis()
+        });
+        if rotate {
+            self.current = None;
+            self.remove_old(sample.timestamp)?;
+            let (path, mut file) = self.create(sample.timestamp)?;
+            file.write_all(MAGIC)
+                .context(format!("Could not write to segment {:?}", path))?;
+            self.current = Some(Segment {
+                file,
+                first_timestamp: sample.timestamp,
+                encoder: Encoder::default(),
+            });
+        }
+        let segment = self.current.as_mut().unwrap();
+        let result = segment.append(sample);
+        if result.is_err() {
+            // whatever follows a partly written record could not be read back
+            self.current = None;
+        }
+        result
+    }
+
+    /// Create a segment named after `timestamp`. A segment with the same name can be left
+    /// by a daemon restarted within the same millisecond, so the name is moved forward then;
+    /// it only has to keep segments in order.
+    fn create(&self, timestamp: u128) -> Result<(PathBuf, File)> {
+        for name in timestamp.. {
+            let path = self.dir.join(format!("{name:020}.{EXTENSION}"));
+            match OpenOptions::new().append(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((path, file)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e).context(format!("Could not create segment {:?}", path)),
+            }
+        }
+        unreachable!("there is always a later timestamp")
+    }
+
+    /// Delete segments which only have samples older than `keep` before `now`.
+    fn remove_old(&self, now: u128) -> Result<()> {
+        let oldest = now.saturating_sub(self.keep.as_millis());
+        let segments = list(&self.dir)?;
+        // a segment lasts until the next one starts
+        for pair in segments.windows(2) {
+            if pair[1].0 <= oldest {
+                fs::remove_file(&pair[0].1)
+                    .context(format!("Could not remove old segment {:?}", pair[0].1))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Segment {
+    fn append(&mut self, sample: &CurrentProcesses) -> Result<()> {
+        let mut records = Vec::new();
+        let sample = self.encoder.encode(sample, |id, process| {
+            records.push(Record::Process {
+                id,
+                process: StoredProcess::of(process),
+            })
+        });
+        records.push(Record::Sample(Box::new(sample)));
+
+        let mut buffer = Vec::new();
+        for record in &records {
+            let start = buffer.len();
+            // length and checksum, filled in once the record is serialized
+            buffer.extend([0; 8]);
+            ciborium::into_writer(record, &mut buffer).context("Could not serialize sample")?;
+            let payload = &buffer[start + 8..];
+            let length = (payload.len() as u32).to_le_bytes();
+            let checksum = crc32fast::hash(payload).to_le_bytes();
+            buffer[start..start + 4].copy_from_slice(&length);
+            buffer[start + 4..start + 8].copy_from_slice(&checksum);
+        }
+        self.file
+            .write_all(&buffer)
+            .and_then(|_| self.file.sync_data())
+            .context("Could not write sample to segment")?;
+        Ok(())
+    }
+}
+
+/// Read all segments in `dir`, oldest first, passing samples to `f`. Returns how many
+/// samples there were. A segment that cannot be read to the end is reported, and samples
+/// before the broken part are still passed on.
+pub fn replay(dir: &Path, mut f: impl FnMut(CurrentProcesses)) -> Result<usize> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let mut count = 0;
+    for (_, path) in list(dir)? {
+        let mut counted = |sample| {
+            count += 1;
+            f(sample)
+        };
+        if let Err(e) = read_segment(&path, &mut counted) {
+            eprintln!("Warning: stopped reading segment {:?}: {:#}", path, e);
+        }
+    }
+    Ok(count)
+}
+
+/// Segments in `dir` with timestamps of their first samples, oldest first.
+fn list(dir: &Path) -> Result<Vec<(u128, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir).context(format!("Could not list data directory {:?}", dir))? {
+        let path = entry
+            .context(format!("Could not list data directory {:?}", dir))?
+            .path();
+        if path.extension() != Some(OsStr::new(EXTENSION)) {
+            continue;
+        }
+        let timestamp = path.file_stem().and_then(|s| s.to_str()?.parse().ok());
+        if let Some(timestamp) = timestamp {
+            segments.push((timestamp, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+fn read_segment(path: &Path, f: &mut impl FnMut(CurrentProcesses)) -> Result<()> {
+    let file = File::open(path).context("Could not open segment")?;
+    let mut reader = BufReader::new(file);
+    let mut magic = vec![0; MAGIC.len()];
+    reader
+        .read_exact(&mut magic)
+        .context("Could not read segment header")?;
+    if magic != MAGIC {
+        return Err(anyhow!("Not a segment of a known version"));
+    }
+    let mut decoder = Decoder::default();
+    while let Some(record) = read_record(&mut reader)? {
+        match record {
+            Record::Process { id, process } => {
+                decoder.add_process(id, Arc::new(process.process()));
+            }
+            Record::Sample(sample) => f(decoder.decode(*sample)?),
+        }
+    }
+    Ok(())
+}
+
+/// Next record, or `None` at the end of the segment.
+fn read_record(reader: &mut impl Read) -> Result<Option<Record>> {
+    let mut header = [0; 8];
+    let mut read = 0;
+    while read < header.len() {
+        match reader.read(&mut header[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(anyhow!("Record header is cut short")),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e).context("Could not read record header"),
+        }
+    }
+    let length = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let mut payload = vec![0; length as usize];
+    reader
+        .read_exact(&mut payload)
+        .context("Record is cut short")?;
+    if crc32fast::hash(&payload) != checksum {
+        return Err(anyhow!("Record has a wrong checksum"));
+    }
+    let record = ciborium::from_reader(payload.as_slice()).context("Could not parse record")?;
+    Ok(Some(record))
+}
+
+impl StoredProcess {
+    fn of(process: &Process) -> Self {
+        let path = |p: &Option<PathBuf>| p.as_ref().map(|p| os_bytes(p.as_os_str()));
+        StoredProcess {
+            pid: process.pid,
+            ppid: process.ppid,
+            exe: path(&process.exe),
+            argv: process.argv.iter().map(|a| os_bytes(a)).collect(),
+            cwd: path(&process.cwd),
+            uid: process.uid,
+            user: process.user.clone(),
+            env: process
+                .env
+                .iter()
+                .map(|(name, value)| (name.clone(), os_bytes(value)))
+                .collect(),
+            cgroup: process.cgroup.clone(),
+            start_time: process.start_time,
+        }
+    }
+
+    fn process(self) -> Process {
+        Process {
+            pid: self.pid,
+            ppid: self.ppid,
+            exe: self.exe.map(|p| os_string(p).into()),
+            argv: self.argv.into_iter().map(os_string).collect(),
+            cwd: self.cwd.map(|p| os_string(p).into()),
+            uid: self.uid,
+            user: self.user,
+            env: self
+                .env
+                .into_iter()
+                .map(|(name, value)| (name, os_string(value)))
+                .collect(),
+            cgroup: self.cgroup,
+            start_time: self.start_time,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::Tier;
+    use crate::events::Event;
+    use crate::process::{list_processes, ProcessCache};
+    use crate::retention::Retention;
+    use crate::source::{ScriptedSample, ScriptedSource};
+
+    fn memory(samples: &[CurrentProcesses]) -> Vec<(u128, Vec<(u32, u64)>)> {
+        samples
+            .iter()
+            .map(|s| {
+                let entries = s.entries.iter();
+                (
+                    s.timestamp,
+                    entries.map(|e| (e.process.pid, e.memory_mb)).collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn restart_and_crash() {
+        let dir = std::env::temp_dir().join(format!("memoir-segments-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut base = ScriptedSample::new(0);
+        for pid in 1..=4 {
+            base = base.with_process(pid, 1, &format!("p{pid} --arg"), 0);
+        }
+        let sample = |timestamp, memory: &[(u32, u64)]| base.at(timestamp, memory).into_sample();
+        let minute = 60_000;
+        let mut samples = vec![
+            sample(0, &[(1, 10), (2, 20)]),
+            sample(1000, &[(1, 10), (2, 25)]),
+            sample(2000, &[(1, 10), (3, 30)]),
+        ];
+        samples[2].events.push(Event::Exited {
+            process: base.processes[1].0.process.clone(),
+            memory_mb: 25,
+        });
+        let mut log = SegmentLog::new(&dir, Duration::from_secs(120)).unwrap();
+        for s in &samples {
+            log.append(s).unwrap();
+        }
+        // a restart starts a new segment, and so does a segment getting older than its span
+        let mut log = SegmentLog::new(&dir, Duration::from_secs(120)).unwrap();
+        samples.push(sample(minute, &[(1, 11)]));
+        samples.push(sample(2 * minute, &[(1, 12)]));
+        samples.push(sample(2 * minute + 1000, &[(1, 12), (4, 40)]));
+        for s in &samples[3..] {
+            log.append(s).unwrap();
+        }
+        assert_eq!(list(&dir).unwrap().len(), 3);
+        // restarted again within the same millisecond as the last sample
+        let mut log = SegmentLog::new(&dir, Duration::from_secs(120)).unwrap();
+        samples.push(sample(2 * minute + 2000, &[(1, 12)]));
+        log.append(&samples[6]).unwrap();
+        let mut log = SegmentLog::new(&dir, Duration::from_secs(120)).unwrap();
+        samples.push(sample(2 * minute + 2000, &[(1, 13)]));
+        log.append(&samples[7]).unwrap();
+        assert_eq!(list(&dir).unwrap().len(), 5);
+
+        let mut read = Vec::new();
+        assert_eq!(replay(&dir, |s| read.push(s)).unwrap(), samples.len());
+        assert_eq!(memory(&read), memory(&samples));
+        assert_eq!(read[2].events[0].process().unwrap().pid, 2);
+        assert_eq!(read[2].entries[0].process.commandline(), "p1 --arg");
+
+        // the last record is torn by a crash
+        let last = list(&dir).unwrap().pop().unwrap().1;
+        let length = fs::metadata(&last).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&last)
+            .unwrap()
+            .set_len(length - 5)
+            .unwrap();
+        let mut read = Vec::new();
+        assert_eq!(replay(&dir, |s| read.push(s)).unwrap(), samples.len() - 1);
+        assert_eq!(memory(&read), memory(&samples[..samples.len() - 1]));
+
+        // segments which only have samples older than 2 minutes go away
+        log.append(&sample(4 * minute, &[(1, 12)])).unwrap();
+        let left: Vec<u128> = list(&dir).unwrap().into_iter().map(|(t, _)| t).collect();
+        let restarted = 2 * minute + 2000;
+        assert_eq!(left, [2 * minute, restarted, restarted + 1, 4 * minute]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bucket_across_restart() {
+        let dir = std::env::temp_dir().join(format!("memoir-restart-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let script = |seconds: std::ops::Range<u128>| {
+            ScriptedSource::new(seconds.map(|s| {
+                ScriptedSample::new(s * 1000)
+                    .with_process(1, 0, "init", 10)
+                    .with_process(2, 1, "server", 20 + s as u64)
+            }))
+        };
+        // every run of the daemon logs a segment of its own
+        for seconds in [0..2, 2..4] {
+            let mut source = script(seconds);
+            let mut cache = ProcessCache::default();
+            let mut log = SegmentLog::new(&dir, Duration::from_secs(3600)).unwrap();
+            for _ in 0..2 {
+                log.append(&list_processes(&mut source, &mut cache).unwrap())
+                    .unwrap();
+            }
+        }
+
+        let tiers = [Tier {
+            resolution: Duration::from_secs(10),
+            keep: Duration::from_secs(3600),
+        }];
+        let mut retention = Retention::new(Duration::from_millis(500), &tiers).unwrap();
+        let mut cache = ProcessCache::default();
+        replay(&dir, |mut sample| {
+            cache.adopt(&mut sample);
+            retention.push(sample, 0);
+        })
+        .unwrap();
+        let mut source = script(4..6);
+        for _ in 0..2 {
+            retention.push(list_processes(&mut source, &mut cache).unwrap(), 0);
+        }
+
+        // samples of both segments and the first one of the new run are in the same bucket
+        let window = retention.window(0);
+        let bucket = window.iter().next().unwrap();
+        let merged: Vec<(u32, u64, u32)> = bucket
+            .entries
+            .iter()
+            .map(|e| (e.process.pid, e.memory_mb, e.spread.unwrap().samples))
+            .collect();
+        assert_eq!(merged, [(1, 10, 5), (2, 22, 5)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
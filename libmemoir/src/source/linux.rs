@@ -0,0 +1,449 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use procfs::FromRead;
+
+use super::ProcessSource;
+use crate::filter::Filter;
+use crate::process::{
+    CgroupMemory, Counters, HistoryEntry, IoCounters, MemoryEvents, Pressure, Process, Stall,
+    StallRecord, SystemMemory,
+};
+
+/// Reads processes from procfs, and memory accounting of cgroups from cgroupfs.
+pub struct ProcfsSource {
+    proc_root: PathBuf,
+    cgroup_root: PathBuf,
+    /// Details read only once per process, by pid and start time in ticks.
+    instances: HashMap<(i32, u64), Instance>,
+    /// Resolved names of users, by uid.
+    users: HashMap<u32, Option<String>>,
+}
+
+/// What is not worth reading again on every iteration.
+struct Instance {
+    cwd: Option<PathBuf>,
+    env: Vec<(String, OsString)>,
+}
+
+impl Default for ProcfsSource {
+    fn default() -> Self {
+        // on hybrid systems cgroup v2 hierarchy is mounted aside of v1 ones
+        let cgroup_root = Path::new("/sys/fs/cgroup");
+        let cgroup_root = if cgroup_root.join("cgroup.controllers").exists() {
+            cgroup_root.to_path_buf()
+        } else {
+            cgroup_root.join("unified")
+        };
+        ProcfsSource {
+            proc_root: PathBuf::from("/proc"),
+            cgroup_root,
+            instances: HashMap::new(),
+            users: HashMap::new(),
+        }
+    }
+}
+
+impl ProcfsSource {
+    /// Read processes from another directory than `/proc`, e.g. a copy of it captured
+    /// on another machine. Cgroups are read from `cgroup` subdirectory of it.
+    pub fn with_root(root: impl Into<PathBuf>) -> Self {
+        let proc_root = root.into();
+        ProcfsSource {
+            cgroup_root: proc_root.join("cgroup"),
+            proc_root,
+            instances: HashMap::new(),
+            users: HashMap::new(),
+        }
+    }
+
+    /// Time of boot in milliseconds since the epoch, from `btime` in `/proc/stat`.
+    fn boot_time_ms(&self) -> Option<u64> {
+        let stat = std::fs::read_to_string(self.proc_root.join("stat")).ok()?;
+        let btime = stat.lines().find_map(|l| l.strip_prefix("btime "))?;
+        btime.trim().parse::<u64>().ok().map(|b| b * 1000)
+    }
+}
+
+impl ProcessSource for ProcfsSource {
+    fn processes(
+        &mut self,
+        filter: &Filter,
+        capture_env: &[String],
+    ) -> Result<Vec<(HistoryEntry, Counters)>> {
+        let page_size: u64 = procfs::page_size();
+        let ticks_per_second: u64 = procfs::ticks_per_second();
+        let boot_time_ms = self.boot_time_ms();
+        let mut entries: Vec<(HistoryEntry, Counters)> = Vec::with_capacity(100);
+        let listed: Vec<_> = procfs::process::all_processes_with_root(&self.proc_root)
+            .context(format!("Could not list processes in {:?}", self.proc_root))?
+            // silently ignore everything we cannot access - processes may die
+            .filter_map(|prc| prc.ok())
+            .filter_map(|prc| prc.stat().ok().map(|stat| (prc, stat)))
+            .collect();
+        let subtree = filter.subtree(
+            listed
+                .iter()
+                .map(|(_, stat)| (stat.pid as u32, stat.ppid as u32)),
+        );
+        let mut seen = HashSet::with_capacity(listed.len());
+        for (prc, stat) in listed {
+            if stat.rss == 0 {
+                continue;
+            }
+            let memory_mb = stat.rss * page_size / 1_000_000;
+            let status = prc.status().ok();
+            // owner of /proc/<pid> is the effective user, and it is the real one we want
+            let uid = match &status {
+                Some(status) => Some(status.ruid),
+                None => prc.uid().ok(),
+            };
+            if !filter.accepts_memory(memory_mb)
+                || !filter.accepts_uid(uid)
+                || subtree
+                    .as_ref()
+                    .is_some_and(|s| !s.contains(&(prc.pid as u32)))
+            {
+                continue;
+            }
+            let key = (stat.pid, stat.starttime);
+            seen.insert(key);
+            let dir = self.proc_root.join(prc.pid.to_string());
+            let instance = self.instances.entry(key).or_insert_with(|| Instance {
+                cwd: prc.cwd().ok(),
+                env: read_env(&dir, capture_env),
+            });
+            let process = Arc::new(Process {
+                pid: prc.pid as u32,
+                ppid: stat.ppid as u32,
+                exe: prc.exe().ok(),
+                argv: read_argv(&dir),
+                cwd: instance.cwd.clone(),
+                env: instance.env.clone(),
+                uid,
+                user: uid.and_then(|uid| {
+                    self.users
+                        .entry(uid)
+                        .or_insert_with(|| super::username(uid))
+                        .clone()
+                }),
+                cgroup: prc.cgroups().ok().and_then(|c| {
+                    c.0.into_iter()
+                        .find(|c| c.hierarchy == 0)
+                        .map(|c| c.pathname)
+                }),
+                // start time in stat is in ticks since boot
+                start_time: boot_time_ms
+                    .map(|boot| boot + stat.starttime * 1000 / ticks_per_second),
+            });
+            if !filter.accepts_process(&process) {
+                continue;
+            }
+
+            // smaps_rollup requires ptrace access to the process, so it is often unreadable
+            // for processes of other users; RSS from stat is still good enough then.
+            let rollup = prc
+                .smaps_rollup()
+                .ok()
+                .and_then(|r| r.memory_map_rollup.0.into_iter().next())
+                .map(|m| m.extension.map);
+            let rollup_mb = |keys: &[&str]| -> Option<u64> {
+                let rollup = rollup.as_ref()?;
+                Some(keys.iter().filter_map(|k| rollup.get(*k)).sum::<u64>() / 1_000_000)
+            };
+
+            let peak_mb = status.and_then(|s| s.vmhwm).map(|kb| kb * 1024 / 1_000_000);
+
+            // /proc/<pid>/io is only readable by the owner of the process
+            let io = prc.io().ok().map(|io| IoCounters {
+                read_bytes: io.read_bytes,
+                write_bytes: io.write_bytes,
+                syscr: io.syscr,
+                syscw: io.syscw,
+            });
+            let counters = Counters {
+                cpu_time_ms: Some((stat.utime + stat.stime) * 1000 / ticks_per_second),
+                io,
+            };
+
+            entries.push((
+                HistoryEntry {
+                    process,
+                    memory_mb,
+                    peak_mb,
+                    pss_mb: rollup_mb(&["Pss"]),
+                    uss_mb: rollup_mb(&["Private_Clean", "Private_Dirty"]),
+                    swap_mb: rollup_mb(&["Swap"]),
+                    cpu_percent: None,
+                    io: None,
+                    state: Some(stat.state),
+                    spread: None,
+                },
+                counters,
+            ))
+        }
+        self.instances.retain(|key, _| seen.contains(key));
+        Ok(entries)
+    }
+
+    fn system_memory(&mut self) -> Option<SystemMemory> {
+        let mb = |bytes: u64| bytes / 1_000_000;
+        let meminfo = procfs::Meminfo::from_file(self.proc_root.join("meminfo")).ok()?;
+        Some(SystemMemory {
+            total_mb: mb(meminfo.mem_total),
+            available_mb: meminfo.mem_available.map(mb),
+            free_mb: mb(meminfo.mem_free),
+            buffers_mb: Some(mb(meminfo.buffers)),
+            cached_mb: Some(mb(meminfo.cached)),
+            shmem_mb: meminfo.shmem.map(mb),
+            slab_mb: Some(mb(meminfo.slab)),
+            swap_total_mb: Some(mb(meminfo.swap_total)),
+            swap_used_mb: Some(mb(meminfo.swap_total.saturating_sub(meminfo.swap_free))),
+        })
+    }
+
+    fn cgroup_memory(&mut self, paths: &[&str]) -> Vec<CgroupMemory> {
+        let mb = |bytes: u64| bytes / 1_000_000;
+        paths
+            .iter()
+            .map(|&path| {
+                let dir = self.cgroup_root.join(path.trim_start_matches('/'));
+                let read = |file: &str| std::fs::read_to_string(dir.join(file)).ok();
+                let read_mb = |file: &str| read(file)?.trim().parse::<u64>().ok().map(mb);
+                CgroupMemory {
+                    path: path.to_string(),
+                    current_mb: read_mb("memory.current"),
+                    // "max" is written there when there is no limit, so it will not parse
+                    max_mb: read_mb("memory.max"),
+                    peak_mb: read_mb("memory.peak"),
+                    events: read("memory.events").map(|e| parse_memory_events(&e)),
+                }
+            })
+            .collect()
+    }
+
+    fn oom_kills(&mut self) -> Option<u64> {
+        // since Linux 4.13
+        let vmstat = procfs::VmStat::from_file(self.proc_root.join("vmstat")).ok()?;
+        let kills = vmstat.0.get("oom_kill").copied()?;
+        Some(kills as u64)
+    }
+
+    /// Read /proc/pressure/*, which only exist since Linux 4.20 and only when the kernel
+    /// was built with CONFIG_PSI and not booted with psi=0.
+    fn pressure(&mut self) -> Option<Pressure> {
+        let record = |r: procfs::PressureRecord| StallRecord {
+            avg10: r.avg10,
+            total_us: r.total,
+        };
+        let dir = self.proc_root.join("pressure");
+        let pressure = Pressure {
+            // "full" line of cpu is either absent, or always zero for the system as a whole
+            cpu: procfs::CpuPressure::from_file(dir.join("cpu"))
+                .ok()
+                .map(|p| Stall {
+                    some: record(p.some),
+                    full: None,
+                }),
+            memory: procfs::MemoryPressure::from_file(dir.join("memory"))
+                .ok()
+                .map(|p| Stall {
+                    some: record(p.some),
+                    full: Some(record(p.full)),
+                }),
+            io: procfs::IoPressure::from_file(dir.join("io"))
+                .ok()
+                .map(|p| Stall {
+                    some: record(p.some),
+                    full: Some(record(p.full)),
+                }),
+        };
+        let any = pressure.resources().iter().any(|(_, s)| s.is_some());
+        any.then_some(pressure)
+    }
+}
+
+/// Read `cmdline` as is, since `procfs` requires it to be UTF-8 and drops empty arguments.
+/// Empty for zombies, and if the process is gone already.
+fn read_argv(dir: &Path) -> Vec<OsString> {
+    let Ok(mut raw) = std::fs::read(dir.join("cmdline")) else {
+        return Vec::new();
+    };
+    // every argument is terminated with NUL, unless the process rewrote them
+    if raw.last() == Some(&0) {
+        raw.pop();
+    }
+    if raw.is_empty() {
+        return Vec::new();
+    }
+    raw.split(|&b| b == 0)
+        .map(|arg| OsString::from_vec(arg.to_vec()))
+        .collect()
+}
+
+/// Read variables named in `names` from `environ`, which is only readable by the owner of
+/// the process. Shows the environment the process was started with, not its current one.
+fn read_env(dir: &Path, names: &[String]) -> Vec<(String, OsString)> {
+    if names.is_empty() {
+        return Vec::new();
+    }
+    let Ok(raw) = std::fs::read(dir.join("environ")) else {
+        return Vec::new();
+    };
+    super::captured_env(names, raw.split(|&b| b == 0).map(OsStr::from_bytes))
+}
+
+fn parse_memory_events(content: &str) -> MemoryEvents {
+    let mut events = MemoryEvents::default();
+    for line in content.lines() {
+        let Some((key, value)) = line.split_once(' ') else {
+            continue;
+        };
+        let Ok(value) = value.trim().parse() else {
+            continue;
+        };
+        match key {
+            "low" => events.low = value,
+            "high" => events.high = value,
+            "max" => events.max = value,
+            "oom" => events.oom = value,
+            "oom_kill" => events.oom_kill = value,
+            _ => {}
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_root() {
+        let mut source = ProcfsSource::with_root("/nonexistent/proc");
+        assert!(source.processes(&Filter::default(), &[]).is_err());
+    }
+
+    #[test]
+    fn replay_captured_proc() {
+        let root = std::env::temp_dir().join(format!("memoir-proc-{}", std::process::id()));
+        let process = root.join("42");
+        std::fs::create_dir_all(&process).unwrap();
+        std::fs::write(root.join("stat"), "cpu  1 2 3 4\nbtime 1700000000\n").unwrap();
+        std::fs::write(root.join("vmstat"), "nr_free_pages 1000\noom_kill 3\n").unwrap();
+        let meminfo = [
+            ("MemTotal", 16_000_000),
+            ("MemFree", 2_000_000),
+            ("MemAvailable", 9_000_000),
+            ("Buffers", 100_000),
+            ("Cached", 6_000_000),
+            ("SwapCached", 0),
+            ("Active", 0),
+            ("Inactive", 0),
+            ("SwapTotal", 4_000_000),
+            ("SwapFree", 3_000_000),
+            ("Dirty", 0),
+            ("Writeback", 0),
+            ("Mapped", 0),
+            ("Shmem", 500_000),
+            ("Slab", 700_000),
+            ("Committed_AS", 0),
+            ("VmallocTotal", 0),
+            ("VmallocUsed", 0),
+            ("VmallocChunk", 0),
+        ];
+        let meminfo: String = meminfo
+            .iter()
+            .map(|(name, kb)| format!("{name}: {kb} kB\n"))
+            .collect();
+        std::fs::write(root.join("meminfo"), meminfo).unwrap();
+        // utime, stime, starttime and rss are 14th, 15th, 22nd and 24th fields
+        std::fs::write(
+            process.join("stat"),
+            "42 (ninja) S 1 42 42 0 -1 4194304 80 0 0 0 50 30 0 0 20 0 1 0 500 2703360 2000 \
+            18446744073709551615 1 1 0 0 0 0 0 0 0 0 0 0 17 0 0 0 0 0 0 0 0 0 0 0 0 0 0\n",
+        )
+        .unwrap();
+        std::fs::write(process.join("cmdline"), b"ninja\0-C\0\0caf\xe9\0").unwrap();
+
+        std::os::unix::fs::symlink("/home/me/checkout", process.join("cwd")).unwrap();
+        std::fs::write(process.join("environ"), "HOME=/home/me\0CI_JOB_ID=42=1\0").unwrap();
+        std::fs::write(
+            process.join("status"),
+            "Name:\tninja\nState:\tS (sleeping)\nTgid:\t42\nPid:\t42\nPPid:\t1\n\
+            TracerPid:\t0\nUid:\t1000\t1000\t1000\t1000\nGid:\t1000\t1000\t1000\t1000\n\
+            FDSize:\t64\nGroups:\t1000\nVmPeak:\t   20000 kB\nVmHWM:\t    9000 kB\n\
+            VmRSS:\t    8000 kB\nThreads:\t1\nSigQ:\t0/63429\nSigPnd:\t0000000000000000\n\
+            ShdPnd:\t0000000000000000\nSigBlk:\t0000000000000000\n\
+            SigIgn:\t0000000000000000\nSigCgt:\t0000000000000000\n\
+            CapInh:\t0000000000000000\nCapPrm:\t0000000000000000\n\
+            CapEff:\t0000000000000000\n",
+        )
+        .unwrap();
+        std::fs::write(
+            process.join("io"),
+            "rchar: 9000\nwchar: 7000\nsyscr: 30\nsyscw: 20\nread_bytes: 8192\n\
+            write_bytes: 4096\ncancelled_write_bytes: 0\n",
+        )
+        .unwrap();
+        std::fs::write(
+            process.join("smaps_rollup"),
+            "00400000-7ffc5f1d7000 ---p 00000000 00:00 0    [rollup]\n\
+            Rss:                8000 kB\n\
+            Pss:                6000 kB\n\
+            Shared_Clean:       5000 kB\n\
+            Private_Clean:      1000 kB\n\
+            Private_Dirty:      2000 kB\n\
+            Swap:               5000 kB\n",
+        )
+        .unwrap();
+
+        let mut source = ProcfsSource::with_root(&root);
+        let capture = ["CI_JOB_ID".to_string(), "BUILD_TARGET".to_string()];
+        let processes = source.processes(&Filter::default(), &capture).unwrap();
+        let oom_kills = source.oom_kills();
+        let system = source.system_memory().unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(oom_kills, Some(3));
+        let mb = |kb: u64| kb * 1024 / 1_000_000;
+        assert_eq!(system.total_mb, mb(16_000_000));
+        assert_eq!(system.available_mb, Some(mb(9_000_000)));
+        assert_eq!(system.free_mb, mb(2_000_000));
+        assert_eq!(system.cached_mb, Some(mb(6_000_000)));
+        assert_eq!(system.shmem_mb, Some(mb(500_000)));
+        assert_eq!(system.slab_mb, Some(mb(700_000)));
+        assert_eq!(system.swap_used_mb, Some(mb(1_000_000)));
+        let [(entry, counters)] = processes.as_slice() else {
+            panic!("Expected exactly one process");
+        };
+        let ticks = procfs::ticks_per_second();
+        assert_eq!(entry.process.pid, 42);
+        assert_eq!(entry.process.ppid, 1);
+        assert_eq!(entry.process.commandline(), r"ninja -C '' $'caf\xe9'");
+        let start_time = 1_700_000_000_000 + 500 * 1000 / ticks;
+        assert_eq!(entry.process.start_time, Some(start_time));
+        assert_eq!(entry.process.instance_id(), format!("42@{start_time}"));
+        assert_eq!(
+            entry.process.cwd.as_deref(),
+            Some(Path::new("/home/me/checkout"))
+        );
+        assert_eq!(entry.state, Some('S'));
+        assert_eq!(entry.process.env, [("CI_JOB_ID".into(), "42=1".into())]);
+        assert_eq!(entry.memory_mb, 2000 * procfs::page_size() / 1_000_000);
+        assert_eq!(counters.cpu_time_ms, Some(80 * 1000 / ticks));
+        let io = counters.io.unwrap();
+        assert_eq!(
+            (io.read_bytes, io.write_bytes, io.syscr, io.syscw),
+            (8192, 4096, 30, 20)
+        );
+        assert_eq!(entry.peak_mb, Some(9000 * 1024 / 1_000_000));
+        assert_eq!(entry.pss_mb, Some(6000 * 1024 / 1_000_000));
+        assert_eq!(entry.uss_mb, Some(3000 * 1024 / 1_000_000));
+        assert_eq!(entry.swap_mb, Some(5000 * 1024 / 1_000_000));
+    }
+}
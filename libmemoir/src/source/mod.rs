@@ -0,0 +1,194 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::filter::Filter;
+use crate::process::{CgroupMemory, Counters, HistoryEntry, Pressure, SystemMemory};
+
+#[cfg(target_os = "linux")]
+mod linux;
+mod scripted;
+#[cfg(feature = "sysinfo")]
+mod sysinfo;
+#[cfg(target_os = "windows")]
+mod windows;
+
+#[cfg(target_os = "linux")]
+pub use linux::ProcfsSource;
+pub use scripted::{ScriptedSample, ScriptedSource};
+#[cfg(feature = "sysinfo")]
+pub use sysinfo::SysinfoSource;
+#[cfg(target_os = "windows")]
+pub use windows::WmiSource;
+
+/// Where information about processes comes from. `list_processes` queries it once per
+/// iteration, starting with `refresh`; everything except the process list is optional.
+pub trait ProcessSource {
+    /// Prepare a new iteration and return its timestamp, in milliseconds since the epoch.
+    fn refresh(&mut self) -> Result<u128> {
+        use std::time::{SystemTime, UNIX_EPOCH};
+        Ok(SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("Time went backwards! TODO: support timey-wimey stuff in memoir")?
+            .as_millis())
+    }
+
+    /// Running processes accepted by `filter`, with their cumulative counters. Rates in
+    /// the entries are computed by `list_processes` from the counters, and left empty here.
+    /// Environment variables named in `capture_env` are read once per process, where
+    /// the OS allows it. Fails only if processes cannot be listed at all; processes that
+    /// cannot be read, e.g. because they exited meanwhile, are skipped.
+    fn processes(
+        &mut self,
+        filter: &Filter,
+        capture_env: &[String],
+    ) -> Result<Vec<(HistoryEntry, Counters)>>;
+
+    /// Memory accounting of given cgroups.
+    fn cgroup_memory(&mut self, _paths: &[&str]) -> Vec<CgroupMemory> {
+        Vec::new()
+    }
+
+    /// Total number of OOM kills since boot.
+    fn oom_kills(&mut self) -> Option<u64> {
+        None
+    }
+
+    fn system_memory(&mut self) -> Option<SystemMemory> {
+        None
+    }
+
+    fn pressure(&mut self) -> Option<Pressure> {
+        None
+    }
+}
+
+/// Values of variables named in `names`, in the same order, from an environment given as
+/// `NAME=value` entries.
+#[cfg(any(target_os = "linux", feature = "sysinfo"))]
+pub(crate) fn captured_env<'a>(
+    names: &[String],
+    environ: impl Iterator<Item = &'a std::ffi::OsStr>,
+) -> Vec<(String, std::ffi::OsString)> {
+    if names.is_empty() {
+        return Vec::new();
+    }
+    let mut values: Vec<Option<&[u8]>> = vec![None; names.len()];
+    for variable in environ {
+        let variable = variable.as_encoded_bytes();
+        let Some(eq) = variable.iter().position(|&b| b == b'=') else {
+            continue;
+        };
+        let (name, value) = (&variable[..eq], &variable[eq + 1..]);
+        if let Some(i) = names.iter().position(|n| n.as_bytes() == name) {
+            values[i] = Some(value);
+        }
+    }
+    names
+        .iter()
+        .zip(values)
+        .filter_map(|(name, value)| {
+            #[cfg(unix)]
+            let value = std::os::unix::ffi::OsStringExt::from_vec(value?.to_vec());
+            // where environment is not bytes, it was converted from UTF-16 and is almost
+            // always valid UTF-8
+            #[cfg(not(unix))]
+            let value = String::from_utf8_lossy(value?).into_owned().into();
+            Some((name.clone(), value))
+        })
+        .collect()
+}
+
+/// Name of the user with given id, from passwd database of the machine memoir runs on.
+#[cfg(unix)]
+pub(crate) fn username(uid: u32) -> Option<String> {
+    // SAFETY: `sysconf` has no preconditions.
+    let suggested = unsafe { libc::sysconf(libc::_SC_GETPW_R_SIZE_MAX) };
+    // SAFETY: `passwd` is plain data, for which all zeroes is a valid value.
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    // the suggested size is not a limit, and may be unknown
+    let mut buffer = vec![0 as libc::c_char; usize::try_from(suggested).unwrap_or(1024)];
+    let mut result = std::ptr::null_mut();
+    loop {
+        // SAFETY: all pointers are valid for the duration of the call, and the buffer
+        // is as long as the size passed along with it.
+        let code = unsafe {
+            libc::getpwuid_r(
+                uid,
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        match code {
+            libc::ERANGE if buffer.len() < 1 << 20 => buffer.resize(buffer.len() * 2, 0),
+            0 => break,
+            _ => return None,
+        }
+    }
+    if result.is_null() {
+        return None;
+    }
+    // SAFETY: on success `pw_name` points to a null-terminated string in `buffer`, which
+    // is still alive.
+    let name = unsafe { std::ffi::CStr::from_ptr(passwd.pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// Source of the OS memoir is running on.
+#[cfg(target_os = "linux")]
+pub fn native() -> Result<ProcfsSource> {
+    Ok(ProcfsSource::default())
+}
+
+/// Source of the OS memoir is running on.
+#[cfg(target_os = "windows")]
+pub fn native() -> Result<WmiSource> {
+    WmiSource::new()
+}
+
+/// Which source the daemon and one-shot commands collect processes from.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// procfs on Linux, WMI on Windows.
+    #[default]
+    Native,
+    /// The `sysinfo` crate; only available when memoir is built with `sysinfo` feature.
+    Sysinfo,
+}
+
+impl std::str::FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "native" => Ok(Backend::Native),
+            "sysinfo" => Ok(Backend::Sysinfo),
+            _ => Err(anyhow!(
+                "Unknown backend {s:?}, expected one of: native, sysinfo"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Backend::Native => write!(f, "native"),
+            Backend::Sysinfo => write!(f, "sysinfo"),
+        }
+    }
+}
+
+/// Create the source for given backend.
+pub fn open(backend: Backend) -> Result<Box<dyn ProcessSource>> {
+    match backend {
+        Backend::Native => Ok(Box::new(native()?)),
+        #[cfg(feature = "sysinfo")]
+        Backend::Sysinfo => Ok(Box::new(SysinfoSource::default())),
+        #[cfg(not(feature = "sysinfo"))]
+        Backend::Sysinfo => Err(anyhow!(
+            "memoir was built without sysinfo backend, rebuild it with `--features sysinfo`"
+        )),
+    }
+}
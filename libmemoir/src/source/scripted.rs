@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use super::ProcessSource;
+use crate::filter::Filter;
+#[cfg(test)]
+use crate::process::CurrentProcesses;
+use crate::process::{CgroupMemory, Counters, HistoryEntry, Pressure, Process, SystemMemory};
+
+/// Everything `ScriptedSource` returns for a single iteration.
+#[derive(Clone, Default)]
+pub struct ScriptedSample {
+    pub timestamp: u128,
+    pub processes: Vec<(HistoryEntry, Counters)>,
+    pub cgroups: Vec<CgroupMemory>,
+    pub oom_kills: Option<u64>,
+    pub system: Option<SystemMemory>,
+    pub pressure: Option<Pressure>,
+}
+
+impl ScriptedSample {
+    pub fn new(timestamp: u128) -> Self {
+        ScriptedSample {
+            timestamp,
+            ..Default::default()
+        }
+    }
+
+    /// Add a process with given RSS and unknown start time. The command line is split on
+    /// spaces, and its first word is used as the executable.
+    pub fn with_process(mut self, pid: u32, ppid: u32, commandline: &str, memory_mb: u64) -> Self {
+        let argv: Vec<OsString> = commandline.split(' ').map(OsString::from).collect();
+        let process = Process {
+            pid,
+            ppid,
+            exe: argv.first().map(PathBuf::from),
+            argv,
+            cwd: None,
+            uid: None,
+            user: None,
+            env: Vec::new(),
+            cgroup: None,
+            start_time: None,
+        };
+        self.processes.push((
+            HistoryEntry {
+                process: Arc::new(process),
+                memory_mb,
+                peak_mb: None,
+                pss_mb: None,
+                uss_mb: None,
+                swap_mb: None,
+                cpu_percent: None,
+                io: None,
+                state: None,
+                spread: None,
+            },
+            Counters::default(),
+        ));
+        self
+    }
+}
+
+#[cfg(test)]
+impl ScriptedSample {
+    /// Put the last added process into given cgroup.
+    pub fn in_cgroup(mut self, cgroup: &str) -> Self {
+        let (entry, _) = self.processes.last_mut().unwrap();
+        Arc::get_mut(&mut entry.process).unwrap().cgroup = Some(cgroup.to_string());
+        self
+    }
+
+    /// The same processes later on, with given memory by pid, and without the ones not
+    /// given. Processes are shared with this sample, like `ProcessCache` would do.
+    pub fn at(&self, timestamp: u128, memory: &[(u32, u64)]) -> Self {
+        let processes = memory.iter().map(|&(pid, memory_mb)| {
+            let (entry, counters) = self
+                .processes
+                .iter()
+                .find(|(e, _)| e.process.pid == pid)
+                .unwrap();
+            let entry = HistoryEntry {
+                memory_mb,
+                ..entry.clone()
+            };
+            (entry, *counters)
+        });
+        ScriptedSample {
+            timestamp,
+            processes: processes.collect(),
+            ..self.clone()
+        }
+    }
+
+    /// The sample as `list_processes` would return it for the first iteration, for tests
+    /// that do not need a source.
+    pub fn into_sample(self) -> CurrentProcesses {
+        CurrentProcesses {
+            timestamp: self.timestamp,
+            interval_ms: None,
+            entries: self.processes.into_iter().map(|(e, _)| e).collect(),
+            system: self.system,
+            pressure: self.pressure,
+            cgroups: self.cgroups,
+            events: Vec::new(),
+        }
+    }
+}
+
+/// Returns prepared samples one per iteration, so that anything built on top of
+/// `list_processes` can be tested without real processes and real time. Once the script
+/// is over, the last sample is repeated forever.
+#[derive(Default)]
+pub struct ScriptedSource {
+    script: VecDeque<ScriptedSample>,
+    current: ScriptedSample,
+}
+
+impl ScriptedSource {
+    pub fn new(script: impl IntoIterator<Item = ScriptedSample>) -> Self {
+        ScriptedSource {
+            script: script.into_iter().collect(),
+            current: ScriptedSample::default(),
+        }
+    }
+}
+
+impl ProcessSource for ScriptedSource {
+    fn refresh(&mut self) -> Result<u128> {
+        if let Some(next) = self.script.pop_front() {
+            self.current = next;
+        }
+        Ok(self.current.timestamp)
+    }
+
+    fn processes(
+        &mut self,
+        filter: &Filter,
+        _capture_env: &[String],
+    ) -> Result<Vec<(HistoryEntry, Counters)>> {
+        let processes = &self.current.processes;
+        let subtree = filter.subtree(
+            processes
+                .iter()
+                .map(|(e, _)| (e.process.pid, e.process.ppid)),
+        );
+        Ok(processes
+            .iter()
+            .filter(|(e, _)| {
+                filter.accepts_memory(e.memory_mb)
+                    && filter.accepts_uid(None)
+                    && subtree.as_ref().is_none_or(|s| s.contains(&e.process.pid))
+                    && filter.accepts_process(&e.process)
+            })
+            .cloned()
+            .collect())
+    }
+
+    fn cgroup_memory(&mut self, paths: &[&str]) -> Vec<CgroupMemory> {
+        let cgroups = self.current.cgroups.iter();
+        cgroups
+            .filter(|c| paths.contains(&c.path.as_str()))
+            .cloned()
+            .collect()
+    }
+
+    fn oom_kills(&mut self) -> Option<u64> {
+        self.current.oom_kills
+    }
+
+    fn system_memory(&mut self) -> Option<SystemMemory> {
+        self.current.system.clone()
+    }
+
+    fn pressure(&mut self) -> Option<Pressure> {
+        self.current.pressure.clone()
+    }
+}
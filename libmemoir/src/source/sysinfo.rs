@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::Result;
+use sysinfo::{ProcessRefreshKind, ProcessStatus, ProcessesToUpdate, System, UpdateKind};
+
+use super::ProcessSource;
+use crate::filter::Filter;
+use crate::process::{Counters, HistoryEntry, Process, SystemMemory};
+
+/// Reads processes through the `sysinfo` crate, which works the same way on every OS it
+/// supports. It knows less than the native sources - no PSS/USS, peak memory, cgroups,
+/// I/O syscalls or OOM kills - but is handy to cross-check them.
+pub struct SysinfoSource {
+    system: System,
+    /// Resolved names of users, by uid.
+    users: HashMap<u32, Option<String>>,
+}
+
+impl Default for SysinfoSource {
+    fn default() -> Self {
+        SysinfoSource {
+            system: System::new(),
+            users: HashMap::new(),
+        }
+    }
+}
+
+impl ProcessSource for SysinfoSource {
+    fn processes(
+        &mut self,
+        filter: &Filter,
+        capture_env: &[String],
+    ) -> Result<Vec<(HistoryEntry, Counters)>> {
+        let mut refresh = ProcessRefreshKind::nothing()
+            .with_memory()
+            .with_cpu()
+            .with_exe(UpdateKind::OnlyIfNotSet)
+            .with_cmd(UpdateKind::OnlyIfNotSet)
+            .with_cwd(UpdateKind::OnlyIfNotSet)
+            .with_user(UpdateKind::OnlyIfNotSet);
+        if !capture_env.is_empty() {
+            refresh = refresh.with_environ(UpdateKind::OnlyIfNotSet);
+        }
+        self.system
+            .refresh_processes_specifics(ProcessesToUpdate::All, true, refresh);
+        let processes = self.system.processes();
+        let subtree = filter.subtree(processes.values().map(|p| {
+            (
+                p.pid().as_u32(),
+                p.parent().map(|pp| pp.as_u32()).unwrap_or(0),
+            )
+        }));
+        let mut entries: Vec<(HistoryEntry, Counters)> = Vec::with_capacity(processes.len());
+        for p in processes.values() {
+            // threads are listed as processes too on Linux
+            if p.thread_kind().is_some() || p.memory() == 0 {
+                continue;
+            }
+            let pid = p.pid().as_u32();
+            let memory_mb = p.memory() / 1_000_000;
+            #[cfg(unix)]
+            let uid: Option<u32> = p.user_id().map(|u| **u);
+            #[cfg(not(unix))]
+            let uid: Option<u32> = None;
+            if !filter.accepts_memory(memory_mb)
+                || !filter.accepts_uid(uid)
+                || subtree.as_ref().is_some_and(|s| !s.contains(&pid))
+            {
+                continue;
+            }
+            let process = Arc::new(Process {
+                pid,
+                ppid: p.parent().map(|pp| pp.as_u32()).unwrap_or(0),
+                // same as native sources: full path to the executable where it is known
+                exe: Some(p.exe().map_or_else(|| p.name().into(), |e| e.to_path_buf())),
+                argv: p.cmd().to_vec(),
+                cwd: p.cwd().map(|c| c.to_path_buf()),
+                env: super::captured_env(capture_env, p.environ().iter().map(|v| v.as_os_str())),
+                uid,
+                #[cfg(unix)]
+                user: uid.and_then(|uid| {
+                    self.users
+                        .entry(uid)
+                        .or_insert_with(|| super::username(uid))
+                        .clone()
+                }),
+                #[cfg(not(unix))]
+                user: None,
+                cgroup: None,
+                // whole seconds, unlike milliseconds of native sources
+                start_time: Some(p.start_time() * 1000),
+            });
+            if !filter.accepts_process(&process) {
+                continue;
+            }
+            entries.push((
+                HistoryEntry {
+                    process,
+                    memory_mb,
+                    peak_mb: None,
+                    pss_mb: None,
+                    uss_mb: None,
+                    swap_mb: None,
+                    cpu_percent: None,
+                    io: None,
+                    state: state(p.status()),
+                    spread: None,
+                },
+                Counters {
+                    cpu_time_ms: Some(p.accumulated_cpu_time()),
+                    io: None,
+                },
+            ));
+        }
+        Ok(entries)
+    }
+
+    fn system_memory(&mut self) -> Option<SystemMemory> {
+        self.system.refresh_memory();
+        let mb = |bytes: u64| bytes / 1_000_000;
+        Some(SystemMemory {
+            total_mb: mb(self.system.total_memory()),
+            available_mb: Some(mb(self.system.available_memory())),
+            free_mb: mb(self.system.free_memory()),
+            buffers_mb: None,
+            cached_mb: None,
+            shmem_mb: None,
+            slab_mb: None,
+            swap_total_mb: Some(mb(self.system.total_swap())),
+            swap_used_mb: Some(mb(self.system.used_swap())),
+        })
+    }
+}
+
+/// Letter of the state like in `ps`, where there is one.
+fn state(status: ProcessStatus) -> Option<char> {
+    match status {
+        ProcessStatus::Run => Some('R'),
+        ProcessStatus::Sleep => Some('S'),
+        ProcessStatus::UninterruptibleDiskSleep => Some('D'),
+        ProcessStatus::Zombie => Some('Z'),
+        ProcessStatus::Stop => Some('T'),
+        ProcessStatus::Tracing => Some('t'),
+        ProcessStatus::Dead => Some('X'),
+        ProcessStatus::Idle => Some('I'),
+        ProcessStatus::Wakekill => Some('K'),
+        ProcessStatus::Waking => Some('W'),
+        ProcessStatus::Parked => Some('P'),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn captures_env() {
+        let mut child = Command::new("sleep")
+            .arg("10")
+            .env("MEMOIR_TEST_VARIABLE", "some value")
+            .spawn()
+            .unwrap();
+        let mut source = SysinfoSource::default();
+        let processes = source
+            .processes(&Filter::default(), &["MEMOIR_TEST_VARIABLE".into()])
+            .unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        let (entry, _) = processes
+            .iter()
+            .find(|(e, _)| e.process.pid == child.id())
+            .unwrap();
+        assert_eq!(
+            entry.process.env,
+            [("MEMOIR_TEST_VARIABLE".to_string(), "some value".into())]
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn start_time_in_seconds() {
+        let pid = std::process::id();
+        let start_time = |source: &mut dyn ProcessSource| {
+            let processes = source.processes(&Filter::default(), &[]).unwrap();
+            let (entry, _) = processes
+                .iter()
+                .find(|(e, _)| e.process.pid == pid)
+                .unwrap();
+            entry.process.start_time.unwrap()
+        };
+        let sysinfo = start_time(&mut SysinfoSource::default());
+        let native = start_time(&mut crate::source::ProcfsSource::default());
+        assert_eq!(sysinfo % 1000, 0);
+        assert_eq!(sysinfo / 1000, native / 1000);
+    }
+}
@@ -0,0 +1,172 @@
+#![allow(non_camel_case_types)]
+#![allow(non_snake_case)]
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use super::ProcessSource;
+use crate::filter::Filter;
+use crate::process::{Counters, HistoryEntry, IoCounters, Process, SystemMemory};
+use crate::quote;
+
+// needs to be named exactly like the entity in WMI
+#[derive(Deserialize, Debug)]
+struct Win32_Process {
+    ProcessId: u32,
+    ParentProcessId: Option<u32>,
+    Name: Option<String>,
+    ExecutablePath: Option<String>,
+    WorkingSetSize: u64,
+    // in kilobytes, unlike WorkingSetSize
+    PeakWorkingSetSize: Option<u32>,
+    // both in 100-nanosecond units
+    KernelModeTime: Option<u64>,
+    UserModeTime: Option<u64>,
+    // Windows does not tell disk I/O from other (network, device) I/O
+    ReadTransferCount: Option<u64>,
+    WriteTransferCount: Option<u64>,
+    ReadOperationCount: Option<u64>,
+    WriteOperationCount: Option<u64>,
+    CommandLine: Option<String>,
+    CreationDate: Option<wmi::WMIDateTime>,
+}
+
+// all sizes are in kilobytes
+#[derive(Deserialize, Debug)]
+struct Win32_OperatingSystem {
+    TotalVisibleMemorySize: u64,
+    FreePhysicalMemory: u64,
+    SizeStoredInPagingFiles: Option<u64>,
+    FreeSpaceInPagingFiles: Option<u64>,
+}
+
+/// Queries processes from WMI, over a connection established once.
+pub struct WmiSource {
+    connection: wmi::WMIConnection,
+}
+
+impl WmiSource {
+    pub fn new() -> Result<Self> {
+        let com_con = wmi::COMLibrary::new().context(
+            "Could not acquire COM library to query WMI.\n\
+            Either you are missing some privilegies, or something is broken in your system.",
+        )?;
+        let connection = wmi::WMIConnection::new(com_con).context(
+            "Could not establish WMI connection.\n\
+            Either you are missing some privilegies, or something is broken in your system.",
+        )?;
+        Ok(WmiSource { connection })
+    }
+}
+
+impl ProcessSource for WmiSource {
+    fn system_memory(&mut self) -> Option<SystemMemory> {
+        let result: Vec<Win32_OperatingSystem> = self.connection.query().ok()?;
+        let os = result.into_iter().next()?;
+        let mb = |kb: u64| kb * 1024 / 1_000_000;
+        Some(SystemMemory {
+            total_mb: mb(os.TotalVisibleMemorySize),
+            available_mb: Some(mb(os.FreePhysicalMemory)),
+            free_mb: mb(os.FreePhysicalMemory),
+            buffers_mb: None,
+            cached_mb: None,
+            shmem_mb: None,
+            slab_mb: None,
+            swap_total_mb: os.SizeStoredInPagingFiles.map(mb),
+            swap_used_mb: match (os.SizeStoredInPagingFiles, os.FreeSpaceInPagingFiles) {
+                (Some(total), Some(free)) => Some(mb(total.saturating_sub(free))),
+                _ => None,
+            },
+        })
+    }
+
+    fn processes(
+        &mut self,
+        filter: &Filter,
+        _capture_env: &[String],
+    ) -> Result<Vec<(HistoryEntry, Counters)>> {
+        let mut entries: Vec<(HistoryEntry, Counters)> = Vec::with_capacity(100);
+        // TODO: Win32_Process.WorkingSetSize is not exactly what we need... Better join with
+        // Win32_PerfRawData_PerfProc_Process on WP.ProcessId == WPRDPPP.IDProcess, and get
+        // WorkingSetPrivate from there.
+        let result: Vec<Win32_Process> = self.connection.query().context(
+            "Could not query WMI for processes.\n\
+                Either you are missing some privilegies, or something is broken in your system.",
+        )?;
+        let subtree = filter.subtree(
+            result
+                .iter()
+                .map(|r| (r.ProcessId, r.ParentProcessId.unwrap_or(0))),
+        );
+        for r in result {
+            let memory_mb = r.WorkingSetSize / 1_000_000;
+            // owner of a process is not queried from WMI, so filtering by user drops everything
+            if !filter.accepts_memory(memory_mb)
+                || !filter.accepts_uid(None)
+                || subtree.as_ref().is_some_and(|s| !s.contains(&r.ProcessId))
+            {
+                continue;
+            }
+            let process = Arc::new(Process {
+                pid: r.ProcessId,
+                ppid: r.ParentProcessId.unwrap_or(0),
+                exe: r.ExecutablePath.or(r.Name).map(PathBuf::from),
+                argv: r
+                    .CommandLine
+                    .map(|c| quote::split_windows(&c))
+                    .unwrap_or_default(),
+                cwd: None,
+                uid: None,
+                user: None,
+                env: Vec::new(),
+                cgroup: None,
+                start_time: r
+                    .CreationDate
+                    .map(|d| d.0.timestamp_millis())
+                    .and_then(|ms| u64::try_from(ms).ok()),
+            });
+            if !filter.accepts_process(&process) {
+                continue;
+            }
+            let counters = Counters {
+                cpu_time_ms: match (r.KernelModeTime, r.UserModeTime) {
+                    (Some(k), Some(u)) => Some((k + u) / 10_000),
+                    _ => None,
+                },
+                io: match (
+                    r.ReadTransferCount,
+                    r.WriteTransferCount,
+                    r.ReadOperationCount,
+                    r.WriteOperationCount,
+                ) {
+                    (Some(rb), Some(wb), Some(rc), Some(wc)) => Some(IoCounters {
+                        read_bytes: rb,
+                        write_bytes: wb,
+                        syscr: rc,
+                        syscw: wc,
+                    }),
+                    _ => None,
+                },
+            };
+
+            entries.push((
+                HistoryEntry {
+                    process,
+                    memory_mb,
+                    peak_mb: r.PeakWorkingSetSize.map(|kb| kb as u64 * 1024 / 1_000_000),
+                    pss_mb: None,
+                    uss_mb: None,
+                    swap_mb: None,
+                    cpu_percent: None,
+                    io: None,
+                    state: None,
+                    spread: None,
+                },
+                counters,
+            ));
+        }
+        Ok(entries)
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::events::Event;
+use crate::history::{members, Members, State, Values};
+use crate::process::{CgroupMemory, CurrentProcesses, Pressure, Process, SystemMemory};
+
+/// A sample as written to disk: relative to the previous one like in `History`, with
+/// processes referenced by ids.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct StoredSample {
+    timestamp: u128,
+    interval_ms: Option<u64>,
+    members: Members,
+    changes: Vec<(u32, Values)>,
+    system: Option<SystemMemory>,
+    pressure: Option<Pressure>,
+    /// `None` if the same as in the previous sample.
+    cgroups: Option<Vec<CgroupMemory>>,
+    events: Vec<StoredEvent>,
+}
+
+/// `Event` with processes referenced by ids.
+#[derive(Serialize, Deserialize)]
+enum StoredEvent {
+    OomKill {
+        cgroup: Option<String>,
+        victim: Option<u32>,
+        memory_mb: Option<u64>,
+    },
+    Started {
+        process: u32,
+        memory_mb: u64,
+    },
+    Exited {
+        process: u32,
+        memory_mb: u64,
+    },
+}
+
+/// Turns consecutive samples into `StoredSample`s, giving ids to processes.
+#[derive(Default)]
+pub(crate) struct Encoder {
+    /// Every process given an id so far, by address. Processes are kept even when they are
+    /// gone from the last sample, since one that comes back must get the same id.
+    ids: HashMap<usize, (u32, Arc<Process>)>,
+    next_id: u32,
+    last: State,
+    cgroups: Vec<CgroupMemory>,
+}
+
+impl Encoder {
+    /// Encode the next sample. `new_process` is called with every process that has got
+    /// an id, before the sample refers to it.
+    pub(crate) fn encode(
+        &mut self,
+        sample: &CurrentProcesses,
+        mut new_process: impl FnMut(u32, &Arc<Process>),
+    ) -> StoredSample {
+        let mut id_of = |process: &Arc<Process>| {
+            let address = Arc::as_ptr(process) as usize;
+            let (id, _) = self.ids.entry(address).or_insert_with(|| {
+                let id = self.next_id;
+                self.next_id += 1;
+                new_process(id, process);
+                (id, process.clone())
+            });
+            *id
+        };
+
+        let mut order = Vec::with_capacity(sample.entries.len());
+        let mut values = HashMap::with_capacity(sample.entries.len());
+        let mut changes = Vec::new();
+        for entry in &sample.entries {
+            let id = id_of(&entry.process);
+            let current = Values::of(entry);
+            if self.last.values.get(&id) != Some(&current) {
+                changes.push((id, current));
+            }
+            order.push(id);
+            values.insert(id, current);
+        }
+        let events = sample
+            .events
+            .iter()
+            .map(|event| match event {
+                Event::OomKill {
+                    cgroup,
+                    victim,
+                    memory_mb,
+                } => StoredEvent::OomKill {
+                    cgroup: cgroup.clone(),
+                    victim: victim.as_ref().map(&mut id_of),
+                    memory_mb: *memory_mb,
+                },
+                Event::Started { process, memory_mb } => StoredEvent::Started {
+                    process: id_of(process),
+                    memory_mb: *memory_mb,
+                },
+                Event::Exited { process, memory_mb } => StoredEvent::Exited {
+                    process: id_of(process),
+                    memory_mb: *memory_mb,
+                },
+            })
+            .collect();
+        let members = if self.last.order.is_empty() {
+            Members::Full(order.clone())
+        } else {
+            members(&self.last.order, &order)
+        };
+        let cgroups = (self.cgroups != sample.cgroups).then(|| sample.cgroups.clone());
+        self.last = State { order, values };
+        self.cgroups = sample.cgroups.clone();
+        StoredSample {
+            timestamp: sample.timestamp,
+            interval_ms: sample.interval_ms,
+            members,
+            changes,
+            system: sample.system.clone(),
+            pressure: sample.pressure.clone(),
+            cgroups,
+            events,
+        }
+    }
+}
+
+/// Turns `StoredSample`s written by `Encoder` back into samples.
+#[derive(Default)]
+pub(crate) struct Decoder {
+    processes: HashMap<u32, Arc<Process>>,
+    state: State,
+    cgroups: Vec<CgroupMemory>,
+}
+
+impl Decoder {
+    pub(crate) fn add_process(&mut self, id: u32, process: Arc<Process>) {
+        self.processes.insert(id, process);
+    }
+
+    pub(crate) fn decode(&mut self, sample: StoredSample) -> Result<CurrentProcesses> {
+        let timestamp = sample.timestamp;
+        let process = |id: u32| {
+            self.processes
+                .get(&id)
+                .cloned()
+                .ok_or_else(|| anyhow!("Sample at {timestamp} refers to unknown process #{id}"))
+        };
+        self.state.apply(&sample.members, &sample.changes);
+        let entries = self
+            .state
+            .order
+            .iter()
+            .map(|id| Ok(self.state.values[id].entry(process(*id)?)))
+            .collect::<Result<_>>()?;
+        let events = sample
+            .events
+            .into_iter()
+            .map(|event| {
+                Ok(match event {
+                    StoredEvent::OomKill {
+                        cgroup,
+                        victim,
+                        memory_mb,
+                    } => Event::OomKill {
+                        cgroup,
+                        victim: victim.map(process).transpose()?,
+                        memory_mb,
+                    },
+                    StoredEvent::Started {
+                        process: id,
+                        memory_mb,
+                    } => Event::Started {
+                        process: process(id)?,
+                        memory_mb,
+                    },
+                    StoredEvent::Exited {
+                        process: id,
+                        memory_mb,
+                    } => Event::Exited {
+                        process: process(id)?,
+                        memory_mb,
+                    },
+                })
+            })
+            .collect::<Result<_>>()?;
+        if let Some(cgroups) = sample.cgroups {
+            self.cgroups = cgroups;
+        }
+        Ok(CurrentProcesses {
+            timestamp,
+            interval_ms: sample.interval_ms,
+            entries,
+            system: sample.system,
+            pressure: sample.pressure,
+            cgroups: self.cgroups.clone(),
+            events,
+        })
+    }
+}
+
+pub(crate) fn os_bytes(s: &OsStr) -> ByteBuf {
+    ByteBuf::from(s.as_encoded_bytes())
+}
+
+/// OS string written with `os_bytes`. On Windows, bytes that are not UTF-8 are replaced.
+pub(crate) fn os_string(bytes: ByteBuf) -> OsString {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        OsString::from_vec(bytes.into_vec())
+    }
+    #[cfg(not(unix))]
+    {
+        String::from_utf8_lossy(&bytes).into_owned().into()
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+use crate::process::{HistoryEntry, MemoryMetric};
+
+/// Parent-child relations between processes of a single sample.
+///
+/// Processes whose parent is not part of the sample (e.g. kernel threads are never listed,
+/// or the parent has already exited) become roots of their own trees.
+pub struct ProcessTree<'a> {
+    entries: &'a [HistoryEntry],
+    family: Family,
+}
+
+/// Parent-child relations between processes given by pids, with processes referred to
+/// by their position.
+pub(crate) struct Family {
+    pids: Vec<u32>,
+    index: HashMap<u32, usize>,
+    /// Children by pid of the parent, including parents that are not listed.
+    children: HashMap<u32, Vec<usize>>,
+    roots: Vec<usize>,
+}
+
+impl Family {
+    /// Relations of processes given as `(pid, ppid)`.
+    pub(crate) fn new(parents: impl Iterator<Item = (u32, u32)>) -> Self {
+        let parents: Vec<(u32, u32)> = parents.collect();
+        let index: HashMap<u32, usize> = parents
+            .iter()
+            .enumerate()
+            .map(|(i, &(pid, _))| (pid, i))
+            .collect();
+        let mut children: HashMap<u32, Vec<usize>> = HashMap::new();
+        let mut roots = Vec::new();
+        for (i, &(pid, ppid)) in parents.iter().enumerate() {
+            if ppid != pid {
+                children.entry(ppid).or_default().push(i);
+            }
+            if ppid == pid || !index.contains_key(&ppid) {
+                roots.push(i);
+            }
+        }
+        Family {
+            pids: parents.into_iter().map(|(pid, _)| pid).collect(),
+            index,
+            children,
+            roots,
+        }
+    }
+
+    fn children_of(&self, pid: u32) -> &[usize] {
+        self.children
+            .get(&pid)
+            .map(|c| c.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Pids of the process with given pid and all of its descendants, even if the process
+    /// itself is not listed.
+    pub(crate) fn descendants_of(&self, pid: u32) -> HashSet<u32> {
+        let mut result = HashSet::from([pid]);
+        let mut stack = self.children_of(pid).to_vec();
+        while let Some(i) = stack.pop() {
+            // a pid might get reused while processes are listed and create a loop
+            if result.insert(self.pids[i]) {
+                stack.extend(self.children_of(self.pids[i]));
+            }
+        }
+        result
+    }
+}
+
+impl<'a> ProcessTree<'a> {
+    pub fn new(entries: &'a [HistoryEntry]) -> Self {
+        ProcessTree {
+            entries,
+            family: Family::new(entries.iter().map(|e| (e.process.pid, e.process.ppid))),
+        }
+    }
+
+    /// The process with given pid and all of its descendants, parents before children.
+    /// Empty if there is no such process in the sample.
+    pub fn subtree(&self, pid: u32) -> Vec<&'a HistoryEntry> {
+        let mut result = Vec::new();
+        if let Some(&root) = self.family.index.get(&pid) {
+            let mut visited = vec![false; self.entries.len()];
+            self.collect(root, &mut visited, &mut result);
+        }
+        result
+    }
+
+    /// Print a pstree-like listing with memory of every process and cumulative memory of its
+    /// subtree. Only the subtree of `root` is printed, if given.
+    pub fn write_to<W: Write>(
+        &self,
+        out: &mut W,
+        metric: MemoryMetric,
+        root: Option<u32>,
+    ) -> std::io::Result<()> {
+        let roots = match root {
+            Some(pid) => self.family.index.get(&pid).copied().into_iter().collect(),
+            None => self.family.roots.clone(),
+        };
+        let mut totals = self.totals(&roots, metric);
+        for r in roots {
+            self.write_node(out, metric, r, "", "", &mut totals)?;
+        }
+        Ok(())
+    }
+
+    fn children_of(&self, i: usize) -> &[usize] {
+        self.family.children_of(self.family.pids[i])
+    }
+
+    // `visited` protects from loops, which might appear if a pid got reused while
+    // the sample was being collected
+    fn collect(&self, i: usize, visited: &mut [bool], result: &mut Vec<&'a HistoryEntry>) {
+        if visited[i] {
+            return;
+        }
+        visited[i] = true;
+        result.push(&self.entries[i]);
+        for &c in self.children_of(i) {
+            self.collect(c, visited, result);
+        }
+    }
+
+    /// Memory used by every process under `roots` together with all of its descendants,
+    /// by position. All of them are added up in a single pass, instead of walking the same
+    /// subtrees again for every process.
+    fn totals(&self, roots: &[usize], metric: MemoryMetric) -> Vec<Option<u64>> {
+        let mut totals = vec![None; self.entries.len()];
+        for &r in roots {
+            self.total(r, metric, &mut totals);
+        }
+        totals
+    }
+
+    /// Memory of the process and its descendants, which gets stored in `totals` for all of
+    /// them. A process already having a total is not counted again, which also protects from
+    /// loops like `visited` does.
+    fn total(&self, i: usize, metric: MemoryMetric, totals: &mut [Option<u64>]) -> u64 {
+        if totals[i].is_some() {
+            return 0;
+        }
+        totals[i] = Some(0);
+        let children: u64 = self
+            .children_of(i)
+            .iter()
+            .map(|&c| self.total(c, metric, totals))
+            .sum();
+        // unknown memory of a process is left out of the total
+        let total = self.entries[i].memory(metric).unwrap_or_default() + children;
+        totals[i] = Some(total);
+        total
+    }
+
+    fn write_node<W: Write>(
+        &self,
+        out: &mut W,
+        metric: MemoryMetric,
+        i: usize,
+        prefix: &str,
+        children_prefix: &str,
+        totals: &mut [Option<u64>],
+    ) -> std::io::Result<()> {
+        // printed processes give their totals away, so none is printed twice
+        let Some(total) = totals[i].take() else {
+            return Ok(());
+        };
+        let entry = &self.entries[i];
+        let name = match &entry.process.exe {
+            Some(exe) => exe.file_name().unwrap_or(exe.as_os_str()).to_string_lossy(),
+            None => "?".into(),
+        };
+        writeln!(
+            out,
+            "{prefix}{name}({}) {} MB / {total} MB",
+            entry.process.pid,
+            entry
+                .memory(metric)
+                .map_or("?".to_string(), |m| m.to_string()),
+        )?;
+        let children = self.children_of(i);
+        for (n, &c) in children.iter().enumerate() {
+            let (branch, continuation) = if n + 1 == children.len() {
+                ("└─", "  ")
+            } else {
+                ("├─", "│ ")
+            };
+            self.write_node(
+                out,
+                metric,
+                c,
+                &format!("{children_prefix}{branch}"),
+                &format!("{children_prefix}{continuation}"),
+                totals,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::ScriptedSample;
+
+    fn sample() -> Vec<HistoryEntry> {
+        ScriptedSample::new(0)
+            .with_process(1, 0, "/sbin/init", 10)
+            .with_process(100, 1, "/usr/bin/ninja", 20)
+            .with_process(101, 100, "/usr/bin/cc1plus", 300)
+            .with_process(102, 100, "/usr/bin/cc1plus", 400)
+            .with_process(103, 102, "/usr/bin/as", 5)
+            // parent is a kernel thread, which is never listed
+            .with_process(200, 2, "/usr/bin/orphan", 1)
+            .into_sample()
+            .entries
+    }
+
+    #[test]
+    fn subtree_total() {
+        let entries = sample();
+        let tree = ProcessTree::new(&entries);
+        let index = &tree.family.index;
+        let totals = tree.totals(&tree.family.roots, MemoryMetric::Rss);
+        let total = |pid| totals[index[&pid]];
+        assert_eq!(total(100), Some(725));
+        assert_eq!(total(102), Some(405));
+        assert_eq!(total(1), Some(735));
+        assert_eq!(total(200), Some(1));
+        // only a subtree
+        let totals = tree.totals(&[index[&102]], MemoryMetric::Rss);
+        assert_eq!(totals[index[&102]], Some(405));
+        assert_eq!(totals[index[&100]], None);
+    }
+
+    #[test]
+    fn listing() {
+        let entries = sample();
+        let tree = ProcessTree::new(&entries);
+        let mut out = Vec::new();
+        tree.write_to(&mut out, MemoryMetric::Rss, None).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&out).unwrap(),
+            "init(1) 10 MB / 735 MB\n\
+            └─ninja(100) 20 MB / 725 MB\n\
+            \x20 ├─cc1plus(101) 300 MB / 300 MB\n\
+            \x20 └─cc1plus(102) 400 MB / 405 MB\n\
+            \x20   └─as(103) 5 MB / 5 MB\n\
+            orphan(200) 1 MB / 1 MB\n"
+        );
+    }
+}
@@ -1,5 +1,7 @@
 extern crate memoir;
 
+use std::time::Duration;
+
 use clap::{Parser, Subcommand};
 
 /// Memoir is a small tool to monitor current RAM consumption on per-process basis
@@ -13,58 +15,389 @@ struct Args {
 #[derive(Subcommand)]
 enum Commands {
     /// get current RAM info, print and exit
-    Once,
+    Once {
+        #[command(flatten)]
+        report: ReportArgs,
+        /// print processes as a tree, with memory used by every process and all its descendants
+        #[arg(long)]
+        tree: bool,
+        /// with --tree, print only this process and its descendants
+        #[arg(long, requires = "tree")]
+        root: Option<u32>,
+        /// take two samples this far apart (e.g. 500ms), so CPU and I/O rates are known
+        #[arg(long, value_parser = parsetime::parse_duration)]
+        interval: Option<Duration>,
+        /// where to collect processes from: native (procfs or WMI) or sysinfo
+        #[arg(long, default_value = "native")]
+        backend: memoir::source::Backend,
+    },
     /// start RAM monitoring
     Run {
         #[arg(short, long)]
         without_checks: bool,
-        /// how many entries / seconds of history to keep
-        #[arg(long, default_value_t = 3600)]
-        #[arg(value_parser = parsetime::parse_time)]
-        keep_history: usize,
+        #[command(flatten)]
+        sampling: SamplingArgs,
     },
     /// start as a detached daemon
     Detach {
-        /// how many entries / seconds of history to keep
-        #[arg(long, default_value_t = 3600)]
-        #[arg(value_parser = parsetime::parse_time)]
-        keep_history: usize,
+        #[command(flatten)]
+        sampling: SamplingArgs,
     },
     /// stop a running daemon
     Stop,
     /// check if daemon is running
     Status,
-    /// save collected RAM report to a file
+    /// save collected RAM report to a file: .memoir for the compact native format,
+    /// .json for JSON, or tab-separated CSV for anything else
     Save {
         /// path to save to
         path: String,
-        /// how many entries / seconds of history to save (save everything, if not specified)
+        /// how many seconds of history to save (save everything, if not specified)
         #[arg(value_parser = parsetime::parse_time)]
         last: Option<usize>,
+        #[command(flatten)]
+        report: ReportArgs,
+    },
+    /// convert a .memoir report into another format, chosen by extension of the output:
+    /// .json for JSON, or tab-separated CSV for anything else
+    Convert {
+        /// .memoir report to read
+        input: String,
+        /// path to save to
+        output: String,
+        #[command(flatten)]
+        report: ReportArgs,
+    },
+    /// list events, like OOM kills or processes starting and exiting, recorded by the daemon
+    Events {
+        /// how many seconds of history to list events from (list everything, if not specified)
+        #[arg(value_parser = parsetime::parse_time)]
+        last: Option<usize>,
+    },
+    /// list every process seen by the daemon with its start, end, peak and average memory
+    Lifetimes {
+        /// how many seconds of history to list processes from (list everything, if not specified)
+        #[arg(value_parser = parsetime::parse_time)]
+        last: Option<usize>,
+        /// which value to report as process memory: rss, pss or uss
+        #[arg(long, default_value = "rss")]
+        metric: memoir::MemoryMetric,
+    },
+    /// run a command and collect RAM report of it and all its descendants, without a daemon;
+    /// exits with the exit code of the command
+    Exec {
+        /// path to save the report to
+        #[arg(short, long)]
+        output: String,
+        #[command(flatten)]
+        report: ReportArgs,
+        /// time between two samples, e.g. 250ms or 5s
+        #[arg(long, default_value = "1s", value_parser = parsetime::parse_duration)]
+        interval: Duration,
+        /// where to collect processes from: native (procfs or WMI) or sysinfo
+        #[arg(long, default_value = "native")]
+        backend: memoir::source::Backend,
+        /// record this environment variable of every process, e.g. CI_JOB_ID, as an extra column;
+        /// can be given several times
+        #[arg(long, value_name = "NAME")]
+        capture_env: Vec<String>,
+        /// command to run, with its arguments
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
     },
     /// forward collected RAM report to stdout
     Dump {
-        /// how many entries / seconds of history to save (save everything, if not specified)
+        /// how many seconds of history to save (save everything, if not specified)
         #[arg(value_parser = parsetime::parse_time)]
         last: Option<usize>,
+        #[command(flatten)]
+        report: ReportArgs,
     },
 }
 
+/// Options of the daemon collecting history
+#[derive(clap::Args)]
+struct SamplingArgs {
+    /// how much time of history to keep, e.g. 3600, 30m or 1h
+    #[arg(long, default_value = "1h", value_parser = parsetime::parse_duration)]
+    keep_history: Duration,
+    /// after --keep-history, merge samples into buckets of RESOLUTION with lowest, average
+    /// and highest memory, and keep them until they are KEEP old, e.g. 10s:1d; can be given
+    /// several times, from the finest to the coarsest
+    #[arg(long, value_name = "RESOLUTION:KEEP", value_parser = parse_tier)]
+    downsample: Vec<memoir::daemon::Tier>,
+    /// time between two samples, e.g. 250ms or 5s
+    #[arg(long, default_value = "1s", value_parser = parsetime::parse_duration)]
+    interval: Duration,
+    /// sample this often while memory usage grows fast, and every --interval otherwise
+    #[arg(long, value_parser = parsetime::parse_duration)]
+    burst_interval: Option<Duration>,
+    /// growth of total memory or of a single process, in MB per second, that switches
+    /// to --burst-interval
+    #[arg(long, default_value_t = 50, requires = "burst_interval")]
+    burst_threshold: u64,
+    #[command(flatten)]
+    filter: FilterArgs,
+    /// where to collect processes from: native (procfs or WMI) or sysinfo
+    #[arg(long, default_value = "native")]
+    backend: memoir::source::Backend,
+    /// record this environment variable of every process, e.g. CI_JOB_ID, as an extra column;
+    /// can be given several times
+    #[arg(long, value_name = "NAME")]
+    capture_env: Vec<String>,
+    /// keep memory taken by history under this size, e.g. 200M or 1G, by downsampling
+    /// or dropping the oldest samples
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_history_memory: Option<u64>,
+    /// log samples to this directory as they are taken, and load them back on start, so
+    /// history survives a restart
+    #[arg(long, value_name = "DIR", value_parser = parse_dir)]
+    data_dir: Option<std::path::PathBuf>,
+}
+
+/// Which processes to record
+#[derive(clap::Args)]
+struct FilterArgs {
+    /// record only processes whose executable or command line matches this regex;
+    /// can be given several times
+    #[arg(long, value_name = "REGEX")]
+    include: Vec<regex::Regex>,
+    /// do not record processes whose executable or command line matches this regex;
+    /// can be given several times
+    #[arg(long, value_name = "REGEX")]
+    exclude: Vec<regex::Regex>,
+    /// record only processes of this user; can be given several times
+    #[arg(long)]
+    uid: Vec<u32>,
+    /// do not record processes using less memory than that, in MB
+    #[arg(long, default_value_t = 0)]
+    min_memory: u64,
+    /// record only this process and its descendants
+    #[arg(long, value_name = "PID")]
+    descendants_of: Option<u32>,
+}
+
+impl FilterArgs {
+    fn filter(&self) -> memoir::Filter {
+        memoir::Filter {
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            uids: self.uid.clone(),
+            min_memory_mb: self.min_memory,
+            descendants_of: self.descendants_of,
+        }
+    }
+}
+
+impl SamplingArgs {
+    fn settings(&self) -> memoir::daemon::Settings {
+        memoir::daemon::Settings {
+            keep_history: self.keep_history,
+            tiers: self.downsample.clone(),
+            interval: self.interval,
+            burst: self.burst_interval.map(|interval| memoir::daemon::Burst {
+                interval,
+                threshold_mb: self.burst_threshold,
+            }),
+            filter: self.filter.filter(),
+            backend: self.backend,
+            capture_env: self.capture_env.clone(),
+            max_history_memory: self.max_history_memory,
+            data_dir: self.data_dir.clone(),
+        }
+    }
+}
+
+fn parse_tier(input: &str) -> anyhow::Result<memoir::daemon::Tier> {
+    let (resolution, keep) = input
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("expected RESOLUTION:KEEP, e.g. 10s:1d"))?;
+    Ok(memoir::daemon::Tier {
+        resolution: parsetime::parse_duration(resolution)?,
+        keep: parsetime::parse_duration(keep)?,
+    })
+}
+
+/// Parses size in bytes, or with K, M or G suffix for kilo-, mega- and gigabytes, with
+/// a megabyte being 1 000 000 bytes like in memory columns.
+/// ```
+/// assert_eq!(parse_size("4096"), Ok(4096))
+/// assert_eq!(parse_size("200M"), Ok(200_000_000))
+/// ```
+fn parse_size(input: &str) -> anyhow::Result<u64> {
+    let (number, unit) = match input.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&input[..i], 1_000),
+        Some((i, 'M' | 'm')) => (&input[..i], 1_000_000),
+        Some((i, 'G' | 'g')) => (&input[..i], 1_000_000_000),
+        _ => (input, 1),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow::anyhow!("expected a size like 200M, got {input:?}"))?;
+    number
+        .checked_mul(unit)
+        .ok_or_else(|| anyhow::anyhow!("size {input:?} is too large"))
+}
+
+/// Makes the path absolute, as a detached daemon runs in another directory.
+fn parse_dir(input: &str) -> anyhow::Result<std::path::PathBuf> {
+    Ok(std::path::absolute(input)?)
+}
+
+/// Options affecting the contents of a report
+#[derive(clap::Args)]
+struct ReportArgs {
+    /// which value to report as process memory: rss, pss or uss
+    #[arg(long, default_value = "rss")]
+    metric: memoir::MemoryMetric,
+    /// add per-second I/O rate columns
+    #[arg(long)]
+    io: bool,
+    /// add system-wide memory values (MemAvailable, Cached, Slab...) as pseudo-processes
+    #[arg(long)]
+    system: bool,
+    /// write one row per cgroup instead of one row per process, with cgroup memory limits
+    #[arg(long)]
+    by_cgroup: bool,
+    /// add UID, user, state and working directory columns
+    #[arg(long)]
+    details: bool,
+    /// report only processes of this user, given by name or UID; can be given several times
+    #[arg(long)]
+    user: Vec<String>,
+    /// report only processes in this state, like D for uninterruptible sleep or Z for zombies;
+    /// can be given several times
+    #[arg(long)]
+    state: Vec<char>,
+    /// report only processes whose working directory matches this regex
+    #[arg(long, value_name = "REGEX")]
+    cwd: Option<regex::Regex>,
+}
+
+impl ReportArgs {
+    fn options(&self) -> memoir::ExportOptions {
+        memoir::ExportOptions {
+            metric: self.metric,
+            io: self.io,
+            system: self.system,
+            by_cgroup: self.by_cgroup,
+            details: self.details,
+            users: self.user.clone(),
+            states: self.state.clone(),
+            cwd: self.cwd.as_ref().map(|r| r.as_str().to_string()),
+        }
+    }
+}
+
 pub fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
     match &args.command {
-        Commands::Once => memoir::control::do_once(),
-        Commands::Detach { keep_history } => memoir::control::do_detach(keep_history.to_owned()),
+        Commands::Once {
+            report,
+            tree,
+            root,
+            interval,
+            backend,
+        } => memoir::control::do_once(&report.options(), *tree, *root, *interval, *backend),
+        Commands::Detach { sampling } => memoir::control::do_detach(&sampling.settings()),
         Commands::Run {
             without_checks,
-            keep_history,
-        } => memoir::control::do_run(!without_checks, keep_history.to_owned()),
+            sampling,
+        } => memoir::control::do_run(!without_checks, &sampling.settings()),
         Commands::Stop => memoir::control::do_stop(),
         Commands::Status => memoir::control::do_status(),
-        Commands::Save { path, last } => memoir::control::do_save(path, *last),
-        Commands::Dump { last } => memoir::control::do_dump(*last),
+        Commands::Save { path, last, report } => {
+            memoir::control::do_save(path, *last, &report.options())
+        }
+        Commands::Convert {
+            input,
+            output,
+            report,
+        } => memoir::control::do_convert(input, output, &report.options()),
+        Commands::Events { last } => memoir::control::do_events(*last),
+        Commands::Lifetimes { last, metric } => memoir::control::do_lifetimes(*last, *metric),
+        Commands::Exec {
+            output,
+            report,
+            interval,
+            backend,
+            capture_env,
+            command,
+        } => {
+            let code = memoir::control::do_exec(
+                command,
+                output,
+                &report.options(),
+                *interval,
+                *backend,
+                capture_env,
+            )?;
+            std::process::exit(code)
+        }
+        Commands::Dump { last, report } => memoir::control::do_dump(*last, &report.options()),
     }
 }
 
 mod parsetime;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memoir::daemon::{Burst, Settings, Tier};
+    use regex::Regex;
+
+    #[test]
+    fn settings_round_trip() {
+        let settings = Settings {
+            keep_history: Duration::from_millis(1500),
+            tiers: vec![Tier {
+                resolution: Duration::from_millis(2500),
+                keep: Duration::from_millis(90_500),
+            }],
+            interval: Duration::from_millis(250),
+            burst: Some(Burst {
+                interval: Duration::from_millis(50),
+                threshold_mb: 20,
+            }),
+            filter: memoir::Filter {
+                include: vec![Regex::new("cc1plus|ninja").unwrap()],
+                exclude: vec![Regex::new("-E ").unwrap(), Regex::new("--help").unwrap()],
+                uids: vec![1000],
+                min_memory_mb: 5,
+                descendants_of: Some(42),
+            },
+            backend: memoir::source::Backend::Native,
+            capture_env: vec!["CI_JOB_ID".to_string()],
+            max_history_memory: Some(200_000_000),
+            data_dir: Some("/var/lib/memoir".into()),
+        };
+        let argv = ["memoirctl", "run"].map(String::from).into_iter();
+        let args = Args::try_parse_from(argv.chain(settings.to_args())).unwrap();
+        let Commands::Run { sampling, .. } = args.command else {
+            panic!("Expected run command");
+        };
+        let parsed = sampling.settings();
+        assert_eq!(parsed.keep_history, settings.keep_history);
+        assert_eq!(parsed.tiers, settings.tiers);
+        assert_eq!(parsed.filter.exclude[0].as_str(), "-E ");
+        assert_eq!(parsed.to_args(), settings.to_args());
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("16k").unwrap(), 16_000);
+        assert_eq!(parse_size("16K").unwrap(), 16_000);
+        assert_eq!(parse_size("200M").unwrap(), 200_000_000);
+        assert_eq!(parse_size("200m").unwrap(), 200_000_000);
+        assert_eq!(parse_size("2G").unwrap(), 2_000_000_000);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("200MB").is_err());
+        assert!(parse_size("-1M").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("18446744074G").is_err());
+    }
+}
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use anyhow::Result;
 
 #[derive(thiserror::Error, Debug, PartialEq, Eq)]
@@ -8,6 +10,10 @@ pub enum ParseError {
     NoDigits,
     #[error("Invalid character #{index}: {value:?}")]
     InvalidCharacter { index: usize, value: char },
+    #[error("Duration cannot be zero")]
+    Zero,
+    #[error("Number is too large")]
+    TooLarge,
 }
 
 /// Parses string representation of time into amount of seconds.
@@ -53,7 +59,7 @@ pub fn parse_time(input: &str) -> Result<usize, ParseError> {
                     units = Units::Days;
                     continue;
                 }
-                _ if c.is_numeric() => {
+                _ if c.is_ascii_digit() => {
                     number_until = Some(i);
                     continue;
                 }
@@ -71,14 +77,43 @@ pub fn parse_time(input: &str) -> Result<usize, ParseError> {
     };
     let value: usize = input[..=number_until]
         .parse()
-        .expect("Should always be able to parse by this point!");
+        .map_err(|_| ParseError::TooLarge)?;
     let multiplier = match units {
         Units::Seconds | Units::Undetermined => 1,
         Units::Minutes => 60,
         Units::Hours => 60 * 60,
         Units::Days => 60 * 60 * 24,
     };
-    Ok(value * multiplier)
+    value.checked_mul(multiplier).ok_or(ParseError::TooLarge)
+}
+
+/// Parses string representation of time into a non-zero duration. Accepts everything
+/// `parse_time` does, and additionally milliseconds.
+/// ```
+/// assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)))
+/// assert_eq!(parse_duration("5s"), Ok(Duration::from_secs(5)))
+/// assert_eq!(parse_duration("1m"), Ok(Duration::from_secs(60)))
+/// assert!(parse_duration("0ms").is_err())
+/// assert!(parse_duration("1.5s").is_err())
+/// ```
+pub fn parse_duration(input: &str) -> Result<Duration, ParseError> {
+    let duration = match input.strip_suffix("ms") {
+        Some(millis) => {
+            if millis.is_empty() {
+                return Err(ParseError::NoDigits);
+            }
+            if let Some((index, value)) = millis.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+                return Err(ParseError::InvalidCharacter { index, value });
+            }
+            let value: u64 = millis.parse().map_err(|_| ParseError::TooLarge)?;
+            Duration::from_millis(value)
+        }
+        None => Duration::from_secs(parse_time(input)? as u64),
+    };
+    if duration.is_zero() {
+        return Err(ParseError::Zero);
+    }
+    Ok(duration)
 }
 
 #[cfg(test)]
@@ -144,4 +179,59 @@ mod tests {
             })
         );
     }
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("1ms"), Ok(Duration::from_millis(1)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("2000ms"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("5"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_duration("5s"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(60 * 60)));
+    }
+    #[test]
+    fn durations_fail() {
+        assert_eq!(parse_duration("0ms"), Err(ParseError::Zero));
+        assert_eq!(parse_duration("0s"), Err(ParseError::Zero));
+        assert_eq!(parse_duration("ms"), Err(ParseError::NoDigits));
+        assert_eq!(
+            parse_duration("1.5s"),
+            Err(ParseError::InvalidCharacter {
+                index: 1,
+                value: '.',
+            })
+        );
+        assert_eq!(
+            parse_duration("٣ms"),
+            Err(ParseError::InvalidCharacter {
+                index: 0,
+                value: '٣',
+            })
+        );
+        assert_eq!(
+            parse_duration("٣s"),
+            Err(ParseError::InvalidCharacter {
+                index: 0,
+                value: '٣',
+            })
+        );
+        assert_eq!(
+            parse_duration("99999999999999999999ms"),
+            Err(ParseError::TooLarge)
+        );
+        assert_eq!(
+            parse_duration("99999999999999999999s"),
+            Err(ParseError::TooLarge)
+        );
+        assert_eq!(
+            parse_duration("999999999999999999d"),
+            Err(ParseError::TooLarge)
+        );
+        assert_eq!(
+            parse_duration("1sms"),
+            Err(ParseError::InvalidCharacter {
+                index: 1,
+                value: 's',
+            })
+        );
+    }
 }